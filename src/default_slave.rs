@@ -12,7 +12,7 @@ use nix::sys::socket::{AddressFamily, connect, MsgFlags, SockAddr, SockFlag};
 use nix::sys::socket::{SockType, socket, UnixAddr, recv};
 use nix::unistd::{close, write};
 use std::os::unix::io::RawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[macro_use]
 extern crate serde_derive;
@@ -31,16 +31,197 @@ const SLAVE_SERVICES: &str = "./samples/services/";
 mod master_slave_shared;
 pub use master_slave_shared::*;
 
+/// A resource limit in a unit file, either a number that is used as both the
+/// soft and hard limit, or a `"soft:hard"` string where either may be
+/// `"infinity"`
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum LimitValue {
+    Number(u64),
+    Text(String),
+}
+
+/// How much of the system a unit may not write to, `true` standing for
+/// `"yes"`: `"yes"` protects /usr and /boot, `"full"` also /etc, and
+/// `"strict"` the whole file system
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum ProtectSystem {
+    Flag(bool),
+    Level(String),
+}
+
+impl ProtectSystem {
+    fn paths(&self) -> std::result::Result<&'static [&'static str], ()> {
+        match *self {
+            ProtectSystem::Flag(false) => Ok(&[]),
+            ProtectSystem::Flag(true) => Ok(&["/usr", "/boot"]),
+            ProtectSystem::Level(ref level) => match level.as_str() {
+                "no" => Ok(&[]),
+                "yes" => Ok(&["/usr", "/boot"]),
+                "full" => Ok(&["/usr", "/boot", "/etc"]),
+                "strict" => Ok(&["/"]),
+                _ => Err(()),
+            },
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 struct Unit {
     pub name: String,
     pub exec_start: String,
 
+    #[serde(rename = "LimitNOFILE", default)]
+    pub limit_nofile: Option<LimitValue>,
+    #[serde(rename = "LimitNPROC", default)]
+    pub limit_nproc: Option<LimitValue>,
+    #[serde(rename = "LimitCORE", default)]
+    pub limit_core: Option<LimitValue>,
+    #[serde(rename = "LimitAS", default)]
+    pub limit_as: Option<LimitValue>,
+    #[serde(rename = "LimitMEMLOCK", default)]
+    pub limit_memlock: Option<LimitValue>,
+    #[serde(rename = "LimitSTACK", default)]
+    pub limit_stack: Option<LimitValue>,
+    #[serde(rename = "LimitCPU", default)]
+    pub limit_cpu: Option<LimitValue>,
+
+    #[serde(default)]
+    pub private_network: bool,
+    #[serde(default)]
+    pub private_tmp: bool,
+    #[serde(default)]
+    pub private_users: bool,
+    #[serde(rename = "PrivateIPC", default)]
+    pub private_ipc: bool,
+    #[serde(rename = "PrivatePIDs", default)]
+    pub private_pids: bool,
+    #[serde(default)]
+    pub private_mounts: bool,
+    #[serde(default)]
+    pub protect_hostname: bool,
+    #[serde(default)]
+    pub protect_system: Option<ProtectSystem>,
+    #[serde(default)]
+    pub read_only_paths: Vec<String>,
+
+    /// `"inherit"`, `"own"` for a process group of its own, or `"session"`
+    #[serde(default)]
+    pub process_group: Option<String>,
+
     #[serde(skip)]
     pub uuid: Uuid,
 }
 
+/// Parses a single limit, `None` standing for `infinity`
+fn parse_limit(s: &str) -> std::result::Result<Option<u64>, ()> {
+    match s.trim() {
+        "infinity" => Ok(None),
+        n => n.parse().map(Some).map_err(|_| ()),
+    }
+}
+
+impl LimitValue {
+    fn to_limit(&self, resource: Resource)
+            -> std::result::Result<ResourceLimit, ()> {
+        let (soft, hard) = match *self {
+            LimitValue::Number(n) => (Some(n), Some(n)),
+            LimitValue::Text(ref s) => match s.split_once(':') {
+                Some((soft, hard)) => (parse_limit(soft)?, parse_limit(hard)?),
+                None => {
+                    let limit = parse_limit(s)?;
+                    (limit, limit)
+                },
+            },
+        };
+
+        Ok(ResourceLimit {
+            resource,
+            soft,
+            hard,
+        })
+    }
+}
+
+impl Unit {
+    /// Collects the resource limits set in the unit file
+    fn resource_limits(&self) -> Vec<ResourceLimit> {
+        let limits = [
+            (Resource::Nofile, &self.limit_nofile),
+            (Resource::Nproc, &self.limit_nproc),
+            (Resource::Core, &self.limit_core),
+            (Resource::As, &self.limit_as),
+            (Resource::Memlock, &self.limit_memlock),
+            (Resource::Stack, &self.limit_stack),
+            (Resource::Cpu, &self.limit_cpu),
+        ];
+
+        limits.iter()
+            .filter_map(|&(resource, value)| {
+                let value = value.as_ref()?;
+                let limit = value.to_limit(resource);
+                if limit.is_err() {
+                    warn!("unit {}: ignoring invalid limit {:?} for {:?}",
+                          self.name, value, resource);
+                }
+                limit.ok()
+            })
+            .collect()
+    }
+
+    /// Collects the namespaces the unit asks for
+    fn namespaces(&self) -> Vec<Namespace> {
+        let namespaces = [
+            (Namespace::Net, self.private_network),
+            (Namespace::User, self.private_users),
+            (Namespace::Ipc, self.private_ipc),
+            (Namespace::Pid, self.private_pids),
+            (Namespace::Mount, self.private_mounts),
+            (Namespace::Uts, self.protect_hostname),
+        ];
+
+        namespaces.iter()
+            .filter(|&&(_, wanted)| wanted)
+            .map(|&(ns, _)| ns)
+            .collect()
+    }
+
+    /// Collects the paths the unit may only read
+    fn read_only_paths(&self) -> Vec<String> {
+        let protected = match self.protect_system {
+            Some(ref protect) => protect.paths().unwrap_or_else(|_| {
+                warn!("unit {}: ignoring invalid ProtectSystem {:?}",
+                      self.name, protect);
+                &[]
+            }),
+            None => &[],
+        };
+
+        /* Not every system has all of the protected paths */
+        protected.iter()
+            .filter(|p| Path::new(p).exists())
+            .map(|p| p.to_string())
+            .chain(self.read_only_paths.iter().cloned())
+            .collect()
+    }
+
+    /// Where the unit runs, relative to the session of sys
+    fn grouping(&self) -> ProcessGrouping {
+        match self.process_group.as_deref() {
+            None | Some("inherit") => ProcessGrouping::Inherit,
+            Some("own") => ProcessGrouping::OwnGroup,
+            Some("session") => ProcessGrouping::Session,
+            Some(other) => {
+                warn!("unit {}: ignoring invalid ProcessGroup {:?}",
+                      self.name, other);
+                ProcessGrouping::Inherit
+            },
+        }
+    }
+}
+
 fn send_request(fd: RawFd, req: Request) -> Result<usize> {
     serialize(&req)
         .or(Err(nix::Error::Sys(nix::errno::Errno::EINVAL)))
@@ -88,9 +269,18 @@ fn construct_startup_plan(units: &Vec<Unit>) -> Option<Vec<&Unit>> {
 /// Note that, whether the unit has "successfully" started is not checked before
 /// the listening loop is started later in the startup procedure.
 fn startup_unit(conn_fd: RawFd, unit: &Unit) {
+    let options = ExecOptions {
+        limits: unit.resource_limits(),
+        namespaces: unit.namespaces(),
+        private_tmp: unit.private_tmp,
+        read_only_paths: unit.read_only_paths(),
+        grouping: unit.grouping(),
+    };
+
     let _ = send_request(conn_fd, Request::UnitStartExecutable(
         unit.uuid,
         unit.exec_start.clone(),
+        options,
     ));
 }
 
@@ -110,9 +300,9 @@ fn handle_connection(fd: RawFd) -> bool {
         a => error!("failed to get counterpart version! {:?}", a),
     }
 
-    let mut units = enumerate_units().unwrap_or(Vec::new());
-    for mut unit in &mut units {
-        register_unit(fd, &mut unit);
+    let mut units = enumerate_units().unwrap_or_default();
+    for unit in &mut units {
+        register_unit(fd, unit);
     }
 
     /* units are now registered, time to start them up */
@@ -129,30 +319,29 @@ fn load_unit_at(path: &PathBuf) -> Result<Unit> {
         return Err(nix::Error::from_errno(nix::errno::Errno::EINVAL));
     }
     let contents = contents.unwrap();
-    let unit = toml::from_str(&contents);
-    if unit.is_ok() {
-        let unit = unit.unwrap();
-        debug!("loaded unit {:?}", unit);
-        return Ok(unit);
-    } else {
-        debug!("load_unit_at: failed to from_str: {:?}", unit);
-        return Err(nix::Error::from_errno(nix::errno::Errno::EINVAL));
+    match toml::from_str(&contents) {
+        Ok(unit) => {
+            debug!("loaded unit {:?}", unit);
+            Ok(unit)
+        },
+        Err(e) => {
+            debug!("load_unit_at: failed to from_str: {:?}", e);
+            Err(nix::Error::from_errno(nix::errno::Errno::EINVAL))
+        },
     }
 }
 
 fn enumerate_units() -> Result<Vec<Unit>> {
     let paths = std::fs::read_dir(SLAVE_SERVICES);
     let mut units: Vec<Unit> = Vec::new();
-    if paths.is_ok() {
-        for path in paths.unwrap() {
+    if let Ok(paths) = paths {
+        for path in paths {
             let path = path.unwrap();
             info!("Loading service {}", path.path().display());
-            let unit = load_unit_at(&path.path());
-            if unit.is_ok() {
-                units.push(unit.unwrap());
-            } else {
-                warn!("failed to load service {}: {:?}",
-                      path.path().display(), unit);
+            match load_unit_at(&path.path()) {
+                Ok(unit) => units.push(unit),
+                Err(e) => warn!("failed to load service {}: {:?}",
+                                path.path().display(), e),
             }
         }
 
@@ -179,7 +368,7 @@ fn main() {
         .expect("FATAL: Failed to connect to master socket");
 
     let r = handle_connection(master_fd);
-    if r == false {
+    if !r {
         let _ = close(master_fd);
     } else {
         /* TODO: reconnect? */
@@ -0,0 +1,58 @@
+/* This file is part of the Aeterno init system. */
+
+/* Passing of file descriptors over unix sockets, shared by -master and -sys.
+ *
+ * File descriptors travel as SCM_RIGHTS ancillary data attached to the bytes
+ * of a protocol line. The kernel installs them in the receiving process as new
+ * descriptors, which are owned by the receiver from then on.
+ */
+
+#![allow(dead_code)]
+
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+
+use nix::sys::socket::{ControlMessage, CmsgSpace, MsgFlags, recvmsg, sendmsg};
+use nix::sys::uio::IoVec;
+
+/// Most file descriptors that can be sent along with a single line
+pub const MAX_FDS_PER_MESSAGE: usize = 8;
+
+/// Sends `data` on the socket `fd`, along with the file descriptors `fds`
+pub fn send_with_fds(fd: RawFd, data: &[u8], fds: &[RawFd])
+        -> nix::Result<usize> {
+    let iov = [IoVec::from_slice(data)];
+
+    if fds.is_empty() {
+        sendmsg(fd, &iov, &[], MsgFlags::empty(), None)
+    } else {
+        let cmsgs = [ControlMessage::ScmRights(fds)];
+        sendmsg(fd, &iov, &cmsgs, MsgFlags::empty(), None)
+    }
+}
+
+/// Receives data from the socket `fd` into `buf`.
+///
+/// Any file descriptors that came along are appended to `fds`, they are
+/// close-on-exec. Returns the number of bytes received.
+pub fn recv_with_fds(fd: RawFd, buf: &mut [u8], fds: &mut Vec<OwnedFd>)
+        -> nix::Result<usize> {
+    let iov = [IoVec::from_mut_slice(buf)];
+    let mut space: CmsgSpace<[RawFd; MAX_FDS_PER_MESSAGE]> = CmsgSpace::new();
+
+    let msg = recvmsg(fd, &iov, Some(&mut space), MsgFlags::MSG_CMSG_CLOEXEC)?;
+
+    for cmsg in msg.cmsgs() {
+        if let ControlMessage::ScmRights(received) = cmsg {
+            /* The kernel just installed these, nobody else owns them */
+            fds.extend(received.iter()
+                       .map(|r| unsafe { OwnedFd::from_raw_fd(*r) }));
+        }
+    }
+
+    if msg.flags.contains(MsgFlags::MSG_CTRUNC) {
+        warn!("more than {} file descriptors sent on FD {}, some were lost",
+              MAX_FDS_PER_MESSAGE, fd);
+    }
+
+    Ok(msg.bytes)
+}
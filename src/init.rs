@@ -1,27 +1,108 @@
 /* This file is part of the Aeterno init system. */
 
 /* Goal:
+ *  - Set up the file systems needed early in the boot
  *  - Create the socket for communication with aeterno-sys
- *  - Exec aeterno-sys
+ *  - Exec aeterno-sys, or an emergency shell if that is not possible
  */
 
 extern crate nix;
 use nix::sys::socket::{AddressFamily, bind, SockAddr, SockFlag, SockType};
 use nix::sys::socket::{socket, UnixAddr};
 
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::ffi::CString;
+use std::path::Path;
+use std::process;
 
+#[path = "init_early.rs"]
+mod early;
+
+#[path = "init_cmdline.rs"]
+mod cmdline;
+
+mod single_instance;
+
+const RUNTIME_DIR: &str = "/run/aeterno";
 const SYS_SOCKET_PATH: &str = "/run/aeterno/sys.sock";
 const SYS_SOCKET_FD: RawFd = 4;
+const SYS_LOCK_PATH: &str = "/run/aeterno/sys.lock";
+/// The lock stays held by aeterno-sys through this descriptor
+const SYS_LOCK_FD: RawFd = 5;
 
+/// Places to look for aeterno-sys, in order, after those given to the kernel
 #[cfg(feature = "native")]
-const AETERNO_SYS_PATH: &str = "/sbin/aeterno-sys";
+const AETERNO_SYS_PATHS: [&str; 3] = [
+    "/sbin/aeterno-sys",
+    "/usr/sbin/aeterno-sys",
+    "/usr/local/sbin/aeterno-sys",
+];
 
 #[cfg(feature = "default")]
-const AETERNO_SYS_PATH: &str = "./target/debug/aeterno-sys";
+const AETERNO_SYS_PATHS: [&str; 2] = [
+    "./target/debug/aeterno-sys",
+    "./target/release/aeterno-sys",
+];
+
+const EMERGENCY_SHELL_PATH: &str = "/bin/sh";
+
+/// Replaces this process with `path`, returns only on failure
+fn exec(path: &str) -> nix::Error {
+    let path = match CString::new(path) {
+        Ok(path) => path,
+        Err(_) => return nix::Error::InvalidPath,
+    };
+
+    let Err(e) = nix::unistd::execvp(&path, std::slice::from_ref(&path));
+    e
+}
+
+/// Leaves the console to the emergency shell, as there is no sys to run
+fn emergency_shell(options: &cmdline::KernelOptions) -> ! {
+    /*
+     * Exiting as PID 1 would panic the kernel, so leave the console to
+     * someone who can fix things up instead.
+     */
+    let shell = options.shell.as_ref()
+        .map_or(EMERGENCY_SHELL_PATH, String::as_str);
+    eprintln!("aeterno-init: starting the emergency shell {} on the console",
+              shell);
+    let e = exec(shell);
+    panic!("FATAL: Failed to start the emergency shell {}, panic inbound: \
+            {:?}", shell, e);
+}
 
 fn main() {
+    let pid1 = nix::unistd::getpid() == nix::unistd::Pid::from_raw(1);
+
+    /* As PID 1, there is nobody to have prepared the system for us */
+    if pid1 {
+        early::mount_filesystems();
+        early::use_console();
+    }
+
+    if let Err(e) = early::create_runtime_dir(Path::new(RUNTIME_DIR)) {
+        eprintln!("aeterno-init: cannot create {}: {}", RUNTIME_DIR, e);
+    }
+
+    let options = cmdline::read();
+
+    /* Make sure we are the only ones and get rid of a stale socket */
+    let lock = match single_instance::claim_socket(Path::new(SYS_SOCKET_PATH),
+                                                   Path::new(SYS_LOCK_PATH)) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("aeterno-init: cannot claim the sys socket, is aeterno \
+                       already running? {}", e);
+            if pid1 {
+                emergency_shell(&options);
+            }
+            process::exit(1);
+        },
+    };
+    nix::unistd::dup2(lock.as_raw_fd(), SYS_LOCK_FD)
+                .expect("FATAL: Failed to dup2(2) the lock fd");
+
     /* Create the socket */
     let sock_fd = socket(AddressFamily::Unix,
                         SockType::Stream,
@@ -40,7 +121,15 @@ fn main() {
                 .expect("FATAL: Failed to dup2(2) the socket fd");
 
     /* Now that the socket has been created, start spawning aeterno-sys */
-    let aeterno_sys_path = CString::new(AETERNO_SYS_PATH).ok().unwrap();
-    nix::unistd::execvp(&aeterno_sys_path, &[])
-                .expect("FATAL: Failed to start aeterno-sys, panic inbound.");
+    let sys_paths = options.sys_paths.iter()
+        .map(String::as_str)
+        .chain(AETERNO_SYS_PATHS.iter().cloned());
+    for path in sys_paths {
+        let e = exec(path);
+        eprintln!("aeterno-init: failed to start aeterno-sys from {}: {:?}",
+                  path, e);
+    }
+
+    eprintln!("aeterno-init: could not start aeterno-sys");
+    emergency_shell(&options);
 }
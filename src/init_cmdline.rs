@@ -0,0 +1,70 @@
+/* This file is part of the Aeterno init system. */
+
+/* Goal:
+ *  - Read the options meant for aeterno from the kernel command line
+ *
+ * Recognized options:
+ *  - aeterno.sys=<path>[,<path>...]: try these aeterno-sys binaries first
+ *  - aeterno.shell=<path>: the shell to start if no aeterno-sys can be run
+ */
+
+use std::fs;
+
+const KERNEL_CMDLINE_PATH: &str = "/proc/cmdline";
+
+#[derive(Debug, Default)]
+pub struct KernelOptions {
+    pub sys_paths: Vec<String>,
+    pub shell: Option<String>,
+}
+
+/// Parses the aeterno options of a kernel command line, ignoring the rest
+pub fn parse(cmdline: &str) -> KernelOptions {
+    let mut options = KernelOptions::default();
+
+    for arg in cmdline.split_whitespace() {
+        match arg.split_once('=') {
+            Some(("aeterno.sys", paths)) => {
+                options.sys_paths.extend(paths.split(',')
+                                         .filter(|p| !p.is_empty())
+                                         .map(str::to_string));
+            },
+            Some(("aeterno.shell", shell)) if !shell.is_empty() => {
+                options.shell = Some(shell.to_string());
+            },
+            _ => (),
+        }
+    }
+
+    options
+}
+
+/// Reads the aeterno options from the kernel command line, if available
+pub fn read() -> KernelOptions {
+    fs::read_to_string(KERNEL_CMDLINE_PATH)
+        .map(|cmdline| parse(&cmdline))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_aeterno_options() {
+        let options = parse("ro aeterno.sys=/a/sys,,/b/sys quiet \
+                             aeterno.shell=/bin/sh aeterno.sys=/c/sys\n");
+
+        assert_eq!(options.sys_paths, vec!["/a/sys", "/b/sys", "/c/sys"]);
+        assert_eq!(options.shell, Some("/bin/sh".to_string()));
+    }
+
+    #[test]
+    fn ignores_other_and_empty_options() {
+        let options = parse("root=/dev/sda aeterno.shell= aeterno.sys \
+                             aeterno.sysx=/x aeterno.sys=");
+
+        assert!(options.sys_paths.is_empty());
+        assert_eq!(options.shell, None);
+    }
+}
@@ -0,0 +1,158 @@
+/* This file is part of the Aeterno init system. */
+
+/* Goal:
+ *  - Mount the file systems everything else expects to be there
+ *  - Create the runtime directory of aeterno
+ *  - Use the console for the standard streams
+ *
+ * Each step is skipped if it has been done already, e.g. by an initramfs or a
+ * container runtime, so that this is safe to run anywhere.
+ */
+
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use nix::fcntl::{open, OFlag};
+use nix::mount::{mount, MsFlags};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, dup2};
+
+const CONSOLE_PATH: &str = "/dev/console";
+
+struct EarlyMount {
+    source: &'static str,
+    target: &'static str,
+    fstype: &'static str,
+    flags: MsFlags,
+    data: Option<&'static str>,
+}
+
+/// The file systems to mount, in order
+fn early_mounts() -> [EarlyMount; 6] {
+    let nosuid_nodev = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+
+    [
+        EarlyMount {
+            source: "proc",
+            target: "/proc",
+            fstype: "proc",
+            flags: nosuid_nodev | MsFlags::MS_NOEXEC,
+            data: None,
+        },
+        EarlyMount {
+            source: "sysfs",
+            target: "/sys",
+            fstype: "sysfs",
+            flags: nosuid_nodev | MsFlags::MS_NOEXEC,
+            data: None,
+        },
+        EarlyMount {
+            source: "devtmpfs",
+            target: "/dev",
+            fstype: "devtmpfs",
+            flags: MsFlags::MS_NOSUID,
+            data: Some("mode=0755"),
+        },
+        EarlyMount {
+            source: "devpts",
+            target: "/dev/pts",
+            fstype: "devpts",
+            flags: MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+            data: Some("gid=5,mode=0620,ptmxmode=0666"),
+        },
+        EarlyMount {
+            source: "tmpfs",
+            target: "/dev/shm",
+            fstype: "tmpfs",
+            flags: nosuid_nodev,
+            data: Some("mode=1777"),
+        },
+        EarlyMount {
+            source: "tmpfs",
+            target: "/run",
+            fstype: "tmpfs",
+            flags: nosuid_nodev,
+            data: Some("mode=0755"),
+        },
+    ]
+}
+
+/// Whether something is mounted on `path`.
+///
+/// This works before /proc is mounted, by checking whether `path` is on
+/// another device than its parent.
+fn is_mount_point(path: &Path) -> io::Result<bool> {
+    let dev = fs::metadata(path)?.dev();
+    let parent_dev = fs::metadata(path.join(".."))?.dev();
+
+    Ok(dev != parent_dev)
+}
+
+fn nix_to_io(e: nix::Error) -> io::Error {
+    match e {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        e => io::Error::other(format!("{:?}", e)),
+    }
+}
+
+fn mount_early(m: &EarlyMount) -> io::Result<()> {
+    let target = Path::new(m.target);
+
+    if target.exists() && is_mount_point(target)? {
+        return Ok(());
+    }
+
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(target)?;
+
+    mount(Some(m.source), target, Some(m.fstype), m.flags, m.data)
+        .map_err(nix_to_io)
+}
+
+/// Mounts /proc, /sys, /dev, /dev/pts, /dev/shm and /run, unless they are
+/// mounted already
+pub fn mount_filesystems() {
+    for m in early_mounts().iter() {
+        if let Err(e) = mount_early(m) {
+            eprintln!("aeterno-init: cannot mount {} on {}: {}",
+                      m.fstype, m.target, e);
+        }
+    }
+}
+
+/// Creates `path`, accessible only by its owner for writing
+pub fn create_runtime_dir(path: &Path) -> io::Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(path)?;
+
+    /* It may have been created with other permissions before */
+    fs::set_permissions(path, Permissions::from_mode(0o755))
+}
+
+/// Makes the console the standard input, output and error
+pub fn use_console() {
+    let fd = match open(CONSOLE_PATH, OFlag::O_RDWR | OFlag::O_NOCTTY,
+                        Mode::empty()) {
+        Ok(fd) => fd,
+        Err(e) => {
+            eprintln!("aeterno-init: cannot open {}: {:?}", CONSOLE_PATH, e);
+            return;
+        },
+    };
+
+    for stdio in 0..3 {
+        if fd != stdio {
+            let _ = dup2(fd, stdio);
+        }
+    }
+
+    if fd > 2 {
+        let _ = close(fd);
+    }
+}
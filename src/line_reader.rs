@@ -0,0 +1,211 @@
+/* This file is part of the Aeterno init system. */
+
+/* Buffered reading of newline-terminated lines, shared by -master and -sys.
+ *
+ * A single read from a stream socket may return several lines, or only part of
+ * one, so lines are buffered until their terminating newline arrives. Lines
+ * longer than the maximum are discarded up to and including their newline, so
+ * that the reader stays in sync with the sender.
+ *
+ * File descriptors passed along with the lines are kept in a queue, in the
+ * order they arrived, until they are taken out with `take_fd`.
+ */
+
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::os::unix::io::{OwnedFd, RawFd};
+
+use nix::errno::Errno;
+
+use fd_passing::recv_with_fds;
+
+const READ_CHUNK: usize = 256;
+/// Most received file descriptors kept around before the oldest are closed
+const PENDING_FDS_MAX: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Line {
+    /// A complete line, without its terminating newline
+    Text(String),
+    /// A line longer than the maximum length was discarded
+    TooLong,
+    /// A line that is not valid UTF-8 was discarded
+    NotUtf8,
+}
+
+pub struct LineReader {
+    fd: RawFd,
+    buf: Vec<u8>,
+    max_len: usize,
+    /// Set while skipping the rest of a line that is too long
+    discarding: bool,
+    /// File descriptors received but not taken yet
+    fds: VecDeque<OwnedFd>,
+}
+
+impl LineReader {
+    /// Creates a reader for `fd` accepting lines of at most `max_len` bytes.
+    pub fn new(fd: RawFd, max_len: usize) -> LineReader {
+        LineReader {
+            fd,
+            buf: Vec::new(),
+            max_len,
+            discarding: false,
+            fds: VecDeque::new(),
+        }
+    }
+
+    /// Takes the oldest file descriptor received and not taken yet.
+    ///
+    /// A file descriptor sent along with a line has been received by the time
+    /// that line is returned by `read_line`.
+    pub fn take_fd(&mut self) -> Option<OwnedFd> {
+        self.fds.pop_front()
+    }
+
+    /// Takes the next complete line out of the buffer, if there is one.
+    fn next_buffered(&mut self) -> Option<Line> {
+        match self.buf.iter().position(|b| *b == b'\n') {
+            Some(pos) => {
+                let mut line: Vec<u8> = self.buf.drain(..=pos).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+
+                if self.discarding || line.len() > self.max_len {
+                    self.discarding = false;
+                    return Some(Line::TooLong);
+                }
+
+                Some(String::from_utf8(line)
+                     .map(Line::Text)
+                     .unwrap_or(Line::NotUtf8))
+            },
+            None => {
+                /* No newline in sight, don't let the buffer grow unbounded */
+                if self.buf.len() > self.max_len {
+                    self.buf.clear();
+                    self.discarding = true;
+                }
+
+                None
+            },
+        }
+    }
+
+    /// Reads the next line, blocking until it is complete.
+    ///
+    /// Returns `Ok(None)` once the other end has closed the connection, any
+    /// unterminated data is dropped at that point.
+    pub fn read_line(&mut self) -> nix::Result<Option<Line>> {
+        loop {
+            if let Some(line) = self.next_buffered() {
+                return Ok(Some(line));
+            }
+
+            let chunk = &mut [0u8; READ_CHUNK];
+            let mut received = Vec::new();
+            let size = match recv_with_fds(self.fd, chunk, &mut received) {
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                res => res?,
+            };
+
+            self.fds.extend(received);
+            while self.fds.len() > PENDING_FDS_MAX {
+                warn!("too many unclaimed file descriptors on FD {}, \
+                       closing the oldest", self.fd);
+                self.fds.pop_front();
+            }
+
+            if size == 0 {
+                return Ok(None);
+            }
+
+            self.buf.extend_from_slice(&chunk[..size]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use fd_passing::send_with_fds;
+
+    fn text(s: &str) -> Option<Line> {
+        Some(Line::Text(s.to_string()))
+    }
+
+    #[test]
+    fn frames_lines() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut reader = LineReader::new(rx.as_raw_fd(), 64);
+
+        /* Several lines at once, then one in pieces */
+        tx.write_all(b"HELO\nSTART /bin/true\r\n\nST").unwrap();
+        assert_eq!(reader.read_line().unwrap(), text("HELO"));
+        assert_eq!(reader.read_line().unwrap(), text("START /bin/true"));
+        assert_eq!(reader.read_line().unwrap(), text(""));
+
+        tx.write_all(b"OP 12").unwrap();
+        tx.write_all(b"34\nunterminated").unwrap();
+        assert_eq!(reader.read_line().unwrap(), text("STOP 1234"));
+
+        drop(tx);
+        assert_eq!(reader.read_line().unwrap(), None);
+    }
+
+    #[test]
+    fn recovers_from_long_lines() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut reader = LineReader::new(rx.as_raw_fd(), 16);
+
+        /* Both within a single read and spanning several of them */
+        tx.write_all(b"0123456789abcdefg\nshort\n").unwrap();
+        tx.write_all(&[b'x'; READ_CHUNK * 3]).unwrap();
+        tx.write_all(b"\n0123456789abcdef\n").unwrap();
+
+        assert_eq!(reader.read_line().unwrap(), Some(Line::TooLong));
+        assert_eq!(reader.read_line().unwrap(), text("short"));
+        assert_eq!(reader.read_line().unwrap(), Some(Line::TooLong));
+        assert_eq!(reader.read_line().unwrap(), text("0123456789abcdef"));
+    }
+
+    #[test]
+    fn discards_invalid_utf8() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut reader = LineReader::new(rx.as_raw_fd(), 16);
+
+        tx.write_all(b"\xff\xfe\nafter\n").unwrap();
+        assert_eq!(reader.read_line().unwrap(), Some(Line::NotUtf8));
+        assert_eq!(reader.read_line().unwrap(), text("after"));
+    }
+
+    #[test]
+    fn queues_passed_fds() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let (mut a, a_peer) = UnixStream::pair().unwrap();
+        let mut reader = LineReader::new(rx.as_raw_fd(), 16);
+
+        send_with_fds(tx.as_raw_fd(), b"FD 3\n", &[a_peer.as_raw_fd()])
+            .unwrap();
+        assert_eq!(reader.read_line().unwrap(), text("FD 3"));
+
+        let fd = reader.take_fd().unwrap();
+        assert!(reader.take_fd().is_none());
+
+        /* It is the same socket that was passed */
+        a.write_all(b"ping").unwrap();
+        let mut passed = File::from(fd);
+        let mut buf = [0u8; 4];
+        passed.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
@@ -19,7 +19,7 @@ extern crate nix;
 use nix::Result;
 use nix::sys::socket::{AddressFamily, connect, bind, listen, SockAddr, SockFlag};
 use nix::sys::socket::{SockType, socket, UnixAddr};
-use nix::unistd::{read, write};
+use nix::unistd::write;
 
 #[macro_use]
 extern crate serde_derive;
@@ -28,7 +28,8 @@ extern crate toml;
 
 use std::cell::RefCell;
 use std::os::unix::io::RawFd;
-use std::process::Command;
+use std::path::Path;
+use std::process::{self, Command};
 use std::sync::Mutex;
 use std::thread;
 
@@ -42,11 +43,34 @@ use uuid::Uuid;
 mod master_slave_shared;
 pub use master_slave_shared::*;
 
+mod quoting;
+
+mod fd_passing;
+
+mod line_reader;
+use line_reader::{Line, LineReader};
+
+mod peer_auth;
+use peer_auth::PeerPolicy;
+
 #[path = "master_slave_comm.rs"]
 pub mod slave_comm;
 
+mod single_instance;
+
 const MASTER_SOCKET_PATH: &str = "/run/aeterno/master.sock";
+const MASTER_LOCK_PATH: &str = "/run/aeterno/master.lock";
 const SYS_SOCKET_PATH: &str = "/run/aeterno/sys.sock";
+/// Longest line accepted from sys, excluding the newline
+const SYS_LINE_MAX: usize = 4096;
+/// The only major version of sys this master can work with
+const SYS_MAJOR_SUPPORTED: u64 = 0;
+/// The first version of sys that lists its capabilities
+const SYS_CAPS_VERSION: SysVersion = SysVersion {
+    major: 0,
+    minor: 1,
+    patch: 0,
+};
 
 #[derive(Debug)]
 struct Slave {
@@ -54,6 +78,7 @@ struct Slave {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 struct Unit {
     pub conn_fd: RawFd,
     pub uuid: Uuid,
@@ -64,6 +89,23 @@ lazy_static! {
         = Mutex::new(RefCell::new(Vec::new()));
 }
 
+lazy_static! {
+    /// Buffered reader for the connection to sys
+    static ref sys_reader: Mutex<RefCell<Option<LineReader>>>
+        = Mutex::new(RefCell::new(None));
+}
+
+lazy_static! {
+    /// Held while sending a START along with its execution context
+    static ref sys_start_lock: Mutex<()> = Mutex::new(());
+}
+
+lazy_static! {
+    /// Capabilities sys listed in reply to `HELO CAPS`
+    static ref sys_caps: Mutex<RefCell<Vec<String>>>
+        = Mutex::new(RefCell::new(Vec::new()));
+}
+
 lazy_static! {
     static ref unit_registry: Mutex<RefCell<Vec<Unit>>>
         = Mutex::new(RefCell::new(Vec::new()));
@@ -79,25 +121,92 @@ struct SysVersion {
 #[derive(Eq, PartialEq, Ord, PartialOrd, Debug)]
 enum SysReply {
     Okay(u64),
-    Error(u64),
+    Error(i64),
+}
+
+/// A wait event reported by sys for one of its children
+#[derive(Eq, PartialEq, Debug)]
+enum SysEvent {
+    /// The process exited with the given exit code
+    Exited(u64, i32),
+    /// The process was terminated by a signal, possibly dumping core
+    Signaled(u64, i32, bool),
+    /// The process was stopped by a signal
+    Stopped(u64, i32),
+    /// The process was resumed
+    Continued(u64),
+    /// The process is gone, but sys does not know how it ended
+    Lost(u64),
+    /// Sys had to drop this many events while no master was connected
+    Dropped(u64),
+    /// The machine is going down
+    Shutdown(ShutdownKind),
+    /// Sys received this signal, configured to be passed on to the master
+    Signal(i32),
+}
+
+/// What the machine does once sys has stopped everything
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum ShutdownKind {
+    Halt,
+    Poweroff,
+    Reboot,
 }
 
 /// Read in the version from the aeterno system by executing a HELO command
+///
+/// Every sys answers a plain HELO, so this is safe to send to any of them.
 fn sys_version(sys_fd: RawFd) -> Result<SysVersion> {
-    let buf = &mut [0u8; 128];
-
     /* retrieve version information */
-    write(sys_fd, "HELO\n".as_bytes())?;
+    sys_request(sys_fd, "HELO\n", parse_sys_version)
+}
 
-    /* read back the information */
-    let len = read(sys_fd, buf)?;
+/// Asks sys for its capabilities with `HELO CAPS`
+///
+/// A sys older than `SYS_CAPS_VERSION` does not know the argument and may not
+/// reply at all, so it is not asked and has no capabilities.
+fn sys_capabilities(sys_fd: RawFd, version: &SysVersion)
+        -> Result<Vec<String>> {
+    if *version < SYS_CAPS_VERSION {
+        return Ok(Vec::new());
+    }
 
-    /* Verify protocol */
-    if len > 8 {
-        let aeterno_str = std::str::from_utf8(buf)
-            .or(Err(nix::Error::Sys(nix::errno::Errno::EINVAL)))?;
+    let reader_cell = sys_reader.lock().unwrap();
+    let mut reader = reader_cell.borrow_mut();
+    let reader = reader.get_or_insert_with(|| {
+        LineReader::new(sys_fd, SYS_LINE_MAX)
+    });
+
+    /* The version line comes first, as for a plain HELO */
+    write(sys_fd, b"HELO CAPS\n")?;
+    read_sys_reply(reader, parse_sys_version)?;
+    read_sys_reply(reader, parse_sys_caps)
+}
 
-        let explosion = aeterno_str.split(|c| c == ' ' || c == '.')
+/// Parses the `CAPS` line sent by sys in reply to `HELO CAPS`
+fn parse_sys_caps(line: &str) -> Result<Vec<String>> {
+    let mut explosion = line.split_whitespace();
+
+    if explosion.next() != Some("CAPS") {
+        return Err(nix::Error::Sys(nix::errno::Errno::EINVAL));
+    }
+
+    Ok(explosion.map(str::to_string).collect())
+}
+
+/// Whether sys listed the capability `cap`
+pub fn sys_has(cap: &str) -> bool {
+    let caps_cell = sys_caps.lock().unwrap();
+    let caps = caps_cell.borrow();
+
+    caps.iter().any(|c| c == cap)
+}
+
+/// Parses the version line sent by sys in reply to HELO
+fn parse_sys_version(aeterno_str: &str) -> Result<SysVersion> {
+    /* Verify protocol */
+    if aeterno_str.len() > 8 {
+        let explosion = aeterno_str.split([' ', '.'])
             .collect::<Vec<_>>();
 
         /* verify that it is Aeterno we are talking to */
@@ -122,18 +231,80 @@ fn sys_version(sys_fd: RawFd) -> Result<SysVersion> {
     }
 }
 
-/// Collects a reply from the socket and parses it
-fn sys_reply(sys_fd: RawFd) -> Result<SysReply> {
-    let buf = &mut [0u8; 128];
+/// Sends a command to sys, then collects its `OK`/`ERR` reply and parses it
+fn sys_command(sys_fd: RawFd, cmd: &str) -> Result<SysReply> {
+    sys_request(sys_fd, cmd, parse_sys_reply)
+}
+
+/// Sends a command to sys and parses the next line that is not an event
+///
+/// The connection is held for the whole exchange, so that concurrent requests
+/// can not steal each other's replies. Wait events that arrive before the
+/// reply are handled on the way.
+fn sys_request<T>(sys_fd: RawFd, cmd: &str, parse: fn(&str) -> Result<T>)
+        -> Result<T> {
+    let reader_cell = sys_reader.lock().unwrap();
+    let mut reader = reader_cell.borrow_mut();
+    let reader = reader.get_or_insert_with(|| {
+        LineReader::new(sys_fd, SYS_LINE_MAX)
+    });
+
+    write(sys_fd, cmd.as_bytes())?;
+    read_sys_reply(reader, parse)
+}
+
+/// Parses the next line from sys that is not an event
+fn read_sys_reply<T>(reader: &mut LineReader, parse: fn(&str) -> Result<T>)
+        -> Result<T> {
+    loop {
+        match reader.read_line()? {
+            Some(Line::Text(ref line)) if line.starts_with("EVENT ") => {
+                handle_sys_event(line);
+            },
+            Some(Line::Text(ref line)) if line.starts_with("LOG") => {
+                debug!("ignoring unrequested output line {:?}", line);
+            },
+            Some(Line::Text(line)) => return parse(&line),
+            Some(Line::TooLong) => {
+                return Err(nix::Error::Sys(nix::errno::Errno::E2BIG));
+            },
+            Some(Line::NotUtf8) => {
+                return Err(nix::Error::Sys(nix::errno::Errno::EINVAL));
+            },
+            None => {
+                return Err(nix::Error::Sys(nix::errno::Errno::ECONNRESET));
+            },
+        }
+    }
+}
 
-    read(sys_fd, buf)?;
+/// Sends the execution context commands `context`, then the START `cmd`.
+///
+/// Sys applies the context to the next START on the connection, so no other
+/// START may be sent in between. Stops at the first context command that fails
+/// and returns its reply.
+fn sys_start(sys_fd: RawFd, context: &[String], cmd: &str)
+        -> Result<SysReply> {
+    let _guard = sys_start_lock.lock().unwrap();
+
+    for c in context {
+        match sys_command(sys_fd, c)? {
+            SysReply::Okay(_) => (),
+            err => {
+                /* Don't leave a partial context behind for the next START */
+                sys_command(sys_fd, "RESET\n")?;
+                return Ok(err);
+            },
+        }
+    }
 
-    /* convert into a string */
-    let converted = std::str::from_utf8(buf)
-        .or(Err(nix::Error::Sys(nix::errno::Errno::EINVAL)))?;
+    sys_command(sys_fd, cmd)
+}
 
+/// Parses a single `OK`/`ERR` reply line
+fn parse_sys_reply(line: &str) -> Result<SysReply> {
     /* explode the string */
-    let explosion = converted.split(|c| c == ' ' || c == '.' || c == '\n')
+    let explosion = line.split([' ', '.'])
         .collect::<Vec<_>>();
 
     /* sanity */
@@ -153,7 +324,7 @@ fn sys_reply(sys_fd: RawFd) -> Result<SysReply> {
             Ok(SysReply::Okay(v))
         },
         "ERR" => {
-            let v = value.parse::<u64>()
+            let v = value.parse::<i64>()
                 .or(Err(nix::Error::Sys(nix::errno::Errno::EINVAL)))?;
             Ok(SysReply::Error(v))
         },
@@ -163,14 +334,79 @@ fn sys_reply(sys_fd: RawFd) -> Result<SysReply> {
     }
 }
 
-/// Asks the sys instance to check whether this connection is a mastering connection
-fn check_mastering(sys_fd: RawFd) -> bool {
-    let r = write(sys_fd, "MASTER\n".as_bytes());
-    if r.is_err() {
-        return false;
+/// Parses a single numeric field of an event line
+fn event_field<T: std::str::FromStr>(field: Option<&&str>) -> Result<T> {
+    field.and_then(|f| f.parse::<T>().ok())
+        .ok_or(nix::Error::Sys(nix::errno::Errno::EINVAL))
+}
+
+/// Parses an event line sent by sys, such as `EVENT EXITED 1234 0`
+fn parse_sys_event(line: &str) -> Result<SysEvent> {
+    /* explode the string */
+    let explosion = line.split_whitespace()
+        .collect::<Vec<_>>();
+
+    /* sanity */
+    if explosion.len() < 3 || explosion[0] != "EVENT" {
+        return Err(nix::Error::Sys(nix::errno::Errno::EINVAL));
+    }
+
+    /* the first value is the pid of the process the event is about */
+    let pid = || event_field(explosion.get(2));
+
+    /* construct the final value */
+    let (event, argc) = match explosion[1] {
+        "EXITED" => {
+            let code = event_field(explosion.get(3))?;
+            (SysEvent::Exited(pid()?, code), 4)
+        },
+        "SIGNALED" => {
+            let sig = event_field(explosion.get(3))?;
+            let core = match event_field::<u8>(explosion.get(4))? {
+                0 => false,
+                1 => true,
+                _ => return Err(nix::Error::Sys(nix::errno::Errno::EINVAL)),
+            };
+            (SysEvent::Signaled(pid()?, sig, core), 5)
+        },
+        "STOPPED" => {
+            let sig = event_field(explosion.get(3))?;
+            (SysEvent::Stopped(pid()?, sig), 4)
+        },
+        "CONTINUED" => (SysEvent::Continued(pid()?), 3),
+        "LOST" => (SysEvent::Lost(pid()?), 3),
+        "DROPPED" => (SysEvent::Dropped(event_field(explosion.get(2))?), 3),
+        "SHUTDOWN" => {
+            let kind = match explosion[2] {
+                "HALT" => ShutdownKind::Halt,
+                "POWEROFF" => ShutdownKind::Poweroff,
+                "REBOOT" => ShutdownKind::Reboot,
+                _ => return Err(nix::Error::Sys(nix::errno::Errno::EINVAL)),
+            };
+            (SysEvent::Shutdown(kind), 3)
+        },
+        "SIGNAL" => (SysEvent::Signal(event_field(explosion.get(2))?), 3),
+        _ => return Err(nix::Error::Sys(nix::errno::Errno::EINVAL)),
+    };
+
+    if explosion.len() != argc {
+        return Err(nix::Error::Sys(nix::errno::Errno::EINVAL));
     }
 
-    let reply = sys_reply(sys_fd);
+    Ok(event)
+}
+
+/// Handles a wait event line received from sys
+fn handle_sys_event(line: &str) {
+    match parse_sys_event(line) {
+        Ok(event) => info!("sys event: {:?}", event),
+        Err(e) => warn!("malformed sys event {:?}: {:?}", line, e),
+    }
+}
+
+/// Asks the sys instance to check whether this connection is a mastering connection
+fn check_mastering(sys_fd: RawFd) -> bool {
+    let reply = sys_command(sys_fd, "MASTER\n");
     if reply.is_err() {
         return false;
     }
@@ -178,28 +414,31 @@ fn check_mastering(sys_fd: RawFd) -> bool {
     /* This unwrap is safe, because of the is_err() check before-hand. */
     let reply = reply.unwrap();
 
-    match reply {
-        SysReply::Okay(_) => true,
-        _ => false,
-    }
+    matches!(reply, SysReply::Okay(_))
 }
 
-/// Registers a slave that was spawned already.
-fn register_slave(slave_id: u64) {
+/// Checks whether `pid` belongs to a slave we started
+pub fn is_registered_slave(pid: u64) -> bool {
     let slave_list = slave_registry.lock().unwrap();
-    let mut slave_list = slave_list.borrow_mut();
+    let slave_list = slave_list.borrow();
 
-    slave_list.push(Slave {
-        pid: slave_id,
-    });
+    slave_list.iter().any(|s| s.pid == pid)
 }
 
 /// Starts the slaves and connects them to this master instance
+///
+/// The registry is held while spawning, so that a slave can not connect
+/// before it has been registered.
 fn start_slaves(config: &MasterConfiguration) {
+    let slave_list = slave_registry.lock().unwrap();
+    let mut slave_list = slave_list.borrow_mut();
+
 	for slave in &config.slaves {
         /* Start the slave */
         if let Ok(child) = Command::new(slave).spawn() {
-            register_slave(child.id() as u64)
+            slave_list.push(Slave {
+                pid: child.id() as u64,
+            });
         } else {
             error!("failed to start slave {:?}", slave);
         }
@@ -220,6 +459,12 @@ fn main() {
     env_logger::init();
     info!("aeterno-master start up");
 
+    /* Make sure we are the only master and get rid of a stale socket */
+    let _lock = single_instance::claim_socket(Path::new(MASTER_SOCKET_PATH),
+                                              Path::new(MASTER_LOCK_PATH))
+                .expect("FATAL: cannot claim the master socket, is another \
+                         master running?");
+
     /* Create master.sock */
     let master_fd = socket(AddressFamily::Unix,
                         SockType::Stream,
@@ -240,12 +485,18 @@ fn main() {
                         None)
         .expect("FATAL: failed to create sys socket counterpair");
 
+    let config = config::parse_config();
+    let policy = match config {
+        Ok(ref c) => PeerPolicy::new(&c.allowed_uids, &c.allowed_gids),
+        Err(_) => PeerPolicy::new(&[], &[]),
+    };
+
     thread::spawn(move || {
         /* Start listening */
         listen(master_fd , 5)
             .expect("FATAL: cannot listen on the Aeterno socket.");
 
-        slave_comm::start_listening(sys_fd, master_fd);
+        slave_comm::start_listening(sys_fd, master_fd, policy);
     });
 
     let sys_unix_addr: UnixAddr = UnixAddr::new(SYS_SOCKET_PATH)
@@ -256,18 +507,29 @@ fn main() {
     if let Ok(ver) = sys_version(sys_fd) {
         info!("Aeterno Sys Version {:?}", ver);
 
+        /* Another major version may not speak the same protocol */
+        if ver.major != SYS_MAJOR_SUPPORTED {
+            error!("FATAL: sys major version {} is not supported, need {}",
+                   ver.major, SYS_MAJOR_SUPPORTED);
+            process::exit(1);
+        }
+
+        match sys_capabilities(sys_fd, &ver) {
+            Ok(caps) => {
+                info!("Aeterno Sys capabilities {:?}", caps);
+                sys_caps.lock().unwrap().replace(caps);
+            },
+            Err(e) => warn!("cannot read the sys capabilities: {:?}", e),
+        }
+
         let mastering = check_mastering(sys_fd);
         if !mastering {
             error!("This master instance is not mastering the aeterno sys");
         } else {
             info!("Acquired sys mastering for this instance");
 
-            let c = config::parse_config();
-			if c.is_ok() {
-				/* This unwrap is safe because of the is_ok() before */
-				let c = c.unwrap();
-
-				start_slaves(&c);
+			if let Ok(ref c) = config {
+				start_slaves(c);
 			} else {
 				error!("failed to parse the master configuration");
 			}
@@ -277,5 +539,78 @@ fn main() {
     }
 
     /* loop away */
-    loop {}
+    loop {
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn einval<T>() -> Result<T> {
+        Err(nix::Error::Sys(nix::errno::Errno::EINVAL))
+    }
+
+    #[test]
+    fn parses_wait_events() {
+        assert_eq!(parse_sys_event("EVENT EXITED 1234 0"),
+                   Ok(SysEvent::Exited(1234, 0)));
+        assert_eq!(parse_sys_event("EVENT EXITED 1234 -1"),
+                   Ok(SysEvent::Exited(1234, -1)));
+        assert_eq!(parse_sys_event("EVENT STOPPED 1234 19"),
+                   Ok(SysEvent::Stopped(1234, 19)));
+        assert_eq!(parse_sys_event("EVENT CONTINUED 1234"),
+                   Ok(SysEvent::Continued(1234)));
+        assert_eq!(parse_sys_event("EVENT LOST 1234"),
+                   Ok(SysEvent::Lost(1234)));
+    }
+
+    #[test]
+    fn parses_the_core_flag() {
+        assert_eq!(parse_sys_event("EVENT SIGNALED 1234 15 0"),
+                   Ok(SysEvent::Signaled(1234, 15, false)));
+        assert_eq!(parse_sys_event("EVENT SIGNALED 1234 11 1"),
+                   Ok(SysEvent::Signaled(1234, 11, true)));
+        assert_eq!(parse_sys_event("EVENT SIGNALED 1234 11 2"), einval());
+        assert_eq!(parse_sys_event("EVENT SIGNALED 1234 11 yes"), einval());
+    }
+
+    #[test]
+    fn parses_other_events() {
+        assert_eq!(parse_sys_event("EVENT DROPPED 17"),
+                   Ok(SysEvent::Dropped(17)));
+        assert_eq!(parse_sys_event("EVENT SHUTDOWN REBOOT"),
+                   Ok(SysEvent::Shutdown(ShutdownKind::Reboot)));
+        assert_eq!(parse_sys_event("EVENT SHUTDOWN POWEROFF"),
+                   Ok(SysEvent::Shutdown(ShutdownKind::Poweroff)));
+        assert_eq!(parse_sys_event("EVENT SHUTDOWN HALT"),
+                   Ok(SysEvent::Shutdown(ShutdownKind::Halt)));
+        assert_eq!(parse_sys_event("EVENT SIGNAL 10"),
+                   Ok(SysEvent::Signal(10)));
+    }
+
+    #[test]
+    fn rejects_wrong_argument_counts() {
+        assert_eq!(parse_sys_event("EVENT EXITED 1234"), einval());
+        assert_eq!(parse_sys_event("EVENT EXITED 1234 0 0"), einval());
+        assert_eq!(parse_sys_event("EVENT SIGNALED 1234 15"), einval());
+        assert_eq!(parse_sys_event("EVENT STOPPED 1234 19 0"), einval());
+        assert_eq!(parse_sys_event("EVENT CONTINUED 1234 0"), einval());
+        assert_eq!(parse_sys_event("EVENT LOST"), einval());
+        assert_eq!(parse_sys_event("EVENT DROPPED"), einval());
+        assert_eq!(parse_sys_event("EVENT DROPPED 1 2"), einval());
+        assert_eq!(parse_sys_event("EVENT SHUTDOWN REBOOT now"), einval());
+        assert_eq!(parse_sys_event("EVENT SIGNAL"), einval());
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert_eq!(parse_sys_event(""), einval());
+        assert_eq!(parse_sys_event("OK 0"), einval());
+        assert_eq!(parse_sys_event("EVENT EXITED pid 0"), einval());
+        assert_eq!(parse_sys_event("EVENT EXITED -1 0"), einval());
+        assert_eq!(parse_sys_event("EVENT SHUTDOWN KEXEC"), einval());
+        assert_eq!(parse_sys_event("EVENT EXPLODED 1234"), einval());
+    }
 }
@@ -14,6 +14,15 @@ const DEFAULT_MASTER_CONFIG: &str = "./samples/master.toml";
 #[derive(Deserialize, Debug)]
 pub struct MasterConfiguration {
     pub slaves: Vec<PathBuf>,
+
+    /// Users allowed to connect to the master socket, besides root and
+    /// the user the master runs as
+    #[serde(default)]
+    pub allowed_uids: Vec<u32>,
+
+    /// Groups allowed to connect to the master socket
+    #[serde(default)]
+    pub allowed_gids: Vec<u32>,
 }
 
 pub fn parse_config() -> Result<MasterConfiguration> {
@@ -1,7 +1,7 @@
 /* This file is part of the Aeterno init system. */
 use bincode::{deserialize, serialize};
 
-use nix::sys::socket::{accept, MsgFlags, recv};
+use nix::sys::socket::{accept4, MsgFlags, recv, SockFlag};
 use nix::unistd::{close, write};
 
 use std::os::unix::io::RawFd;
@@ -9,11 +9,17 @@ use std::thread;
 
 use uuid::Uuid;
 
-mod master_slave_shared;
-use master_slave_shared::{Reply, Request};
+use ::master_slave_shared::{ExecOptions, Reply, Request};
+use ::master_slave_shared::{Namespace, ProcessGrouping, Resource};
+use ::master_slave_shared::ResourceLimit;
+use ::peer_auth::PeerPolicy;
+use ::quoting;
 
 use ::SysReply::*;
 
+/// Largest request accepted from a slave
+const SLAVE_REQUEST_MAX: usize = 4096;
+
 fn handle_helo(conn_fd: RawFd) -> bool {
     let helo = Reply::Helo("aeterno-master 0.0.1 - November 2018".to_string());
     let encoded: Vec<u8> = serialize(&helo).unwrap();
@@ -34,20 +40,98 @@ fn handle_register_unit(conn_fd: RawFd) -> bool {
     false
 }
 
+/// Formats a resource limit as the `LIMIT` command of sys
+fn limit_command(limit: &ResourceLimit) -> String {
+    let name = match limit.resource {
+        Resource::Nofile => "NOFILE",
+        Resource::Nproc => "NPROC",
+        Resource::Core => "CORE",
+        Resource::As => "AS",
+        Resource::Memlock => "MEMLOCK",
+        Resource::Stack => "STACK",
+        Resource::Cpu => "CPU",
+    };
+    let value = |v: Option<u64>| {
+        v.map_or_else(|| "infinity".to_string(), |v| v.to_string())
+    };
+
+    format!("LIMIT {} {} {}\n", name, value(limit.soft), value(limit.hard))
+}
+
+/// Formats the namespaces of a unit as the `UNSHARE` command of sys
+fn unshare_command(namespaces: &[Namespace]) -> String {
+    let names = namespaces.iter()
+        .map(|ns| match *ns {
+            Namespace::Mount => "MOUNT",
+            Namespace::Pid => "PID",
+            Namespace::Net => "NET",
+            Namespace::Uts => "UTS",
+            Namespace::Ipc => "IPC",
+            Namespace::User => "USER",
+        })
+        .collect::<Vec<_>>();
+
+    format!("UNSHARE {}\n", names.join(" "))
+}
+
 fn handle_unit_start_executable(sys_fd: RawFd, conn_fd: RawFd, uuid: Uuid,
-                                execstr: String) -> bool {
+                                execstr: String, options: ExecOptions)
+        -> bool {
     debug!("Handling Start request for fd {} uuid {} execstr \"{}\"",
            conn_fd, uuid, execstr);
 
-    let _ = write(sys_fd, format!("START {}", execstr).as_bytes());
+    let argv = match quoting::split(&execstr) {
+        Ok(ref argv) if !argv.is_empty() => quoting::join(argv),
+        res => {
+            error!("invalid executable \"{}\" for uuid {}: {:?}",
+                   execstr, uuid, res);
+            return false;
+        },
+    };
 
-    /* We should now receive either `ERR XX` or `OK XX`,
+    /* We should receive either `ERR XX` or `OK XX`,
      * where in the case of `ERR`, `XX` is the errno from the execve(2) call.
      *
      * In the case of `OK`, the `XX` is the PID of the process created.
      */
-    use ::sys_reply;
-    let res = sys_reply(sys_fd);
+    /* Don't start the unit with less than it asked for */
+    use ::sys_has;
+    if !options.limits.is_empty() && !sys_has("LIMIT") {
+        error!("sys can not apply the resource limits of uuid {}", uuid);
+        return false;
+    }
+    if (!options.namespaces.is_empty() || options.private_tmp
+        || !options.read_only_paths.is_empty()) && !sys_has("ISOLATION") {
+        error!("sys can not isolate uuid {}", uuid);
+        return false;
+    }
+
+    use ::sys_start;
+    let mut context = Vec::new();
+    /* Only tags the process, so an older sys can do without */
+    if sys_has("UNIT") {
+        context.push(format!("UNIT {}\n", uuid));
+    }
+    context.extend(options.limits.iter().map(limit_command));
+    if !options.namespaces.is_empty() {
+        context.push(unshare_command(&options.namespaces));
+    }
+    if options.private_tmp {
+        context.push("PRIVATETMP\n".to_string());
+    }
+    context.extend(options.read_only_paths.iter()
+                   .map(|p| format!("READONLY {}\n", quoting::quote(p))));
+    match options.grouping {
+        ProcessGrouping::Inherit => (),
+        /* Better started in the group of sys than not at all */
+        _ if !sys_has("PGROUP") => {
+            warn!("sys can not move uuid {} out of its process group", uuid);
+        },
+        ProcessGrouping::OwnGroup => context.push("SETPGID\n".to_string()),
+        ProcessGrouping::Session => context.push("SETSID\n".to_string()),
+    }
+
+    let res = sys_start(sys_fd, &context, &format!("START {}\n", argv));
 
     match res {
         Ok(Okay(pid)) => info!("spawned process with pid {}", pid),
@@ -62,14 +146,15 @@ fn handle_request(sys_fd: RawFd, conn_fd: RawFd, req: Request) -> bool {
     match req {
         Request::Helo => handle_helo(conn_fd),
         Request::RegisterUnit => handle_register_unit(conn_fd),
-        Request::UnitStartExecutable(uuid, execstr)
-            => handle_unit_start_executable(sys_fd, conn_fd, uuid, execstr),
+        Request::UnitStartExecutable(uuid, execstr, options)
+            => handle_unit_start_executable(sys_fd, conn_fd, uuid, execstr,
+                                            options),
         _ => true,
     }
 }
 
 fn handle_connection(sys_fd: RawFd, conn_fd: RawFd) {
-    let buf: &mut [u8] = &mut [0; 256];
+    let buf: &mut [u8] = &mut [0; SLAVE_REQUEST_MAX];
 
     loop {
         let size = recv(conn_fd, buf, MsgFlags::empty());
@@ -85,14 +170,37 @@ fn handle_connection(sys_fd: RawFd, conn_fd: RawFd) {
     }
 }
 
+/// Checks that the peer on `conn_fd` is allowed and is one of our slaves
+fn authenticate_slave(policy: &PeerPolicy, conn_fd: RawFd) -> bool {
+    let cred = match policy.authenticate(conn_fd) {
+        Some(cred) => cred,
+        None => return false,
+    };
+
+    use ::is_registered_slave;
+    if !is_registered_slave(cred.pid() as u64) {
+        warn!("peer on FD {} (pid {}) is not a registered slave",
+              conn_fd, cred.pid());
+        return false;
+    }
+
+    true
+}
+
 /// Start listening on a socket, expecting connections from slaves
 ///
 /// Assumes that listening has already been setup by the caller.
 #[allow(dead_code)]
-pub fn start_listening(sys_fd: RawFd, fd: RawFd) {
+pub fn start_listening(sys_fd: RawFd, fd: RawFd, policy: PeerPolicy) {
     loop {
-        if let Ok(conn_fd) = accept(fd) {
+        if let Ok(conn_fd) = accept4(fd, SockFlag::SOCK_CLOEXEC) {
             debug!("Accepted a connection with FD {}", conn_fd);
+
+            if !authenticate_slave(&policy, conn_fd) {
+                let _ = close(conn_fd);
+                continue;
+            }
+
             thread::spawn(move || {
                 handle_connection(sys_fd, conn_fd);
             });
@@ -5,7 +5,7 @@ use uuid::Uuid;
 pub enum Request {
     Helo,
     RegisterUnit,
-    UnitStartExecutable(Uuid, String),
+    UnitStartExecutable(Uuid, String, ExecOptions),
     ProtocolError,
 }
 
@@ -15,3 +15,58 @@ pub enum Reply {
     UnitRegistered(Uuid),
 }
 
+/// Resources that can be limited for the executable of a unit
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum Resource {
+    Nofile,
+    Nproc,
+    Core,
+    As,
+    Memlock,
+    Stack,
+    Cpu,
+}
+
+/// A soft and hard resource limit, `None` standing for no limit
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct ResourceLimit {
+    pub resource: Resource,
+    pub soft: Option<u64>,
+    pub hard: Option<u64>,
+}
+
+/// Namespaces the executable of a unit can be given of its own
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum Namespace {
+    Mount,
+    Pid,
+    Net,
+    Uts,
+    Ipc,
+    User,
+}
+
+/// Where the executable of a unit runs, relative to the session and process
+/// group of sys
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ProcessGrouping {
+    /// In the session and process group of sys
+    #[default]
+    Inherit,
+    /// In a process group of its own
+    OwnGroup,
+    /// In a session of its own
+    Session,
+}
+
+/// How the executable of a unit is to be started
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct ExecOptions {
+    pub limits: Vec<ResourceLimit>,
+    pub namespaces: Vec<Namespace>,
+    /// Mount an empty /tmp that only the unit can see
+    pub private_tmp: bool,
+    /// Paths that the unit can only read
+    pub read_only_paths: Vec<String>,
+    pub grouping: ProcessGrouping,
+}
@@ -0,0 +1,108 @@
+/* This file is part of the Aeterno init system. */
+
+/* Authentication of peers on the aeterno sockets, shared by -master and -sys.
+ *
+ * The kernel reports the credentials of the process on the other end of a
+ * unix socket (SO_PEERCRED), a peer is let in if either its user or its group
+ * is allowed. root and the user we run as are always allowed, since they could
+ * take over this process anyway.
+ */
+
+#![allow(dead_code)]
+
+use std::env;
+use std::os::unix::io::RawFd;
+
+use nix::sys::socket::{getsockopt, UnixCredentials};
+use nix::sys::socket::sockopt::PeerCredentials;
+use nix::unistd::geteuid;
+
+#[derive(Debug, Clone)]
+pub struct PeerPolicy {
+    allowed_uids: Vec<u32>,
+    allowed_gids: Vec<u32>,
+}
+
+/// Parses a comma separated list of numeric ids, skipping invalid entries
+fn parse_ids(list: &str) -> Vec<u32> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .filter_map(|id| {
+            let parsed = id.parse().ok();
+            if parsed.is_none() {
+                warn!("ignoring invalid id {:?}", id);
+            }
+            parsed
+        })
+        .collect()
+}
+
+impl PeerPolicy {
+    /// Allows the given users and groups, in addition to root and ourselves
+    pub fn new(uids: &[u32], gids: &[u32]) -> PeerPolicy {
+        let mut allowed_uids = vec![0, u32::from(geteuid())];
+        allowed_uids.extend_from_slice(uids);
+
+        PeerPolicy {
+            allowed_uids,
+            allowed_gids: gids.to_vec(),
+        }
+    }
+
+    /// Reads the allowed users and groups from comma separated lists in the
+    /// environment variables `uid_var` and `gid_var`
+    pub fn from_env(uid_var: &str, gid_var: &str) -> PeerPolicy {
+        let uids = env::var(uid_var)
+            .map(|v| parse_ids(&v))
+            .unwrap_or_default();
+        let gids = env::var(gid_var)
+            .map(|v| parse_ids(&v))
+            .unwrap_or_default();
+
+        PeerPolicy::new(&uids, &gids)
+    }
+
+    pub fn allows(&self, cred: &UnixCredentials) -> bool {
+        self.allowed_uids.contains(&cred.uid())
+            || self.allowed_gids.contains(&cred.gid())
+    }
+
+    /// Checks the peer connected on `fd`.
+    ///
+    /// Returns its credentials if it is allowed to talk to us, `None` if it
+    /// is not or its credentials can not be retrieved.
+    pub fn authenticate(&self, fd: RawFd) -> Option<UnixCredentials> {
+        match getsockopt(fd, PeerCredentials) {
+            Ok(cred) => {
+                if self.allows(&cred) {
+                    Some(cred)
+                } else {
+                    warn!("peer on FD {} (pid {}, uid {}, gid {}) is not allowed",
+                          fd, cred.pid(), cred.uid(), cred.gid());
+                    None
+                }
+            },
+            Err(e) => {
+                warn!("failed to get peer credentials of FD {}: {:?}", fd, e);
+                None
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ids() {
+        assert_eq!(parse_ids("1000, 1001,,42"), vec![1000, 1001, 42]);
+        assert_eq!(parse_ids(""), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn skips_invalid_ids() {
+        assert_eq!(parse_ids("root,-1,4294967296,7,0x10"), vec![7]);
+    }
+}
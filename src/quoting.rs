@@ -0,0 +1,181 @@
+/* This file is part of the Aeterno init system. */
+
+/* Shell-like quoting of command arguments, shared by -master and -sys.
+ *
+ * Arguments are separated by unquoted spaces or tabs. Within an argument:
+ *  - `"..."` groups characters, including whitespace and single quotes
+ *  - `'...'` groups characters literally, backslashes included
+ *  - `\` outside of single quotes escapes the next character, where `\n` and
+ *    `\t` stand for a newline and a tab respectively
+ * Quoted and unquoted parts may be mixed, e.g. `FOO="a b"` is `FOO=a b`.
+ */
+
+#![allow(dead_code)]
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QuotingError {
+    /// A `"` or `'` was never closed
+    UnterminatedQuote,
+    /// The input ended with a lone `\`
+    TrailingBackslash,
+}
+
+fn unescape(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        c => c,
+    }
+}
+
+/// Splits `s` into its arguments.
+pub fn split(s: &str) -> Result<Vec<String>, QuotingError> {
+    let mut args = Vec::new();
+    let mut chars = s.chars();
+
+    /* `None` while between arguments */
+    let mut current: Option<String> = None;
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => {
+                if let Some(arg) = current.take() {
+                    args.push(arg);
+                }
+            },
+            '\\' => {
+                let escaped = chars.next()
+                    .ok_or(QuotingError::TrailingBackslash)?;
+                current.get_or_insert_with(String::new)
+                    .push(unescape(escaped));
+            },
+            '"' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            let escaped = chars.next()
+                                .ok_or(QuotingError::UnterminatedQuote)?;
+                            arg.push(unescape(escaped));
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(QuotingError::UnterminatedQuote),
+                    }
+                }
+            },
+            '\'' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(QuotingError::UnterminatedQuote),
+                    }
+                }
+            },
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if let Some(arg) = current {
+        args.push(arg);
+    }
+
+    Ok(args)
+}
+
+/// Whether `c` can appear in an argument without quoting.
+fn is_plain(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_-+=/.,:@%".contains(c)
+}
+
+/// Quotes a single argument, so that `split` turns it back into `arg`.
+pub fn quote(arg: &str) -> String {
+    if !arg.is_empty() && arg.chars().all(is_plain) {
+        return arg.to_string();
+    }
+
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    for c in arg.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            },
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// Quotes every argument in `args` and joins them with spaces.
+pub fn join<S: AsRef<str>>(args: &[S]) -> String {
+    args.iter()
+        .map(|arg| quote(arg.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(args: &[&str]) {
+        let joined = join(args);
+        assert_eq!(split(&joined).unwrap(), args, "joined as {:?}", joined);
+    }
+
+    #[test]
+    fn split_plain() {
+        assert_eq!(split("/bin/echo Hello,  World!").unwrap(),
+                   vec!["/bin/echo", "Hello,", "World!"]);
+        assert_eq!(split("").unwrap(), Vec::<String>::new());
+        assert_eq!(split("  \t ").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn split_quoted() {
+        assert_eq!(split(r#"/bin/echo "Hello, World!""#).unwrap(),
+                   vec!["/bin/echo", "Hello, World!"]);
+        assert_eq!(split(r#"sh -c 'echo "$HOME" \n'"#).unwrap(),
+                   vec!["sh", "-c", r#"echo "$HOME" \n"#]);
+        assert_eq!(split(r#"FOO="a b"c '' """#).unwrap(),
+                   vec!["FOO=a bc", "", ""]);
+    }
+
+    #[test]
+    fn split_escapes() {
+        assert_eq!(split(r#"a\ b "c\"d" e\\f g\nh"#).unwrap(),
+                   vec!["a b", "c\"d", "e\\f", "g\nh"]);
+    }
+
+    #[test]
+    fn split_errors() {
+        assert_eq!(split(r#"echo "foo"#), Err(QuotingError::UnterminatedQuote));
+        assert_eq!(split("echo 'foo"), Err(QuotingError::UnterminatedQuote));
+        assert_eq!(split("echo foo\\"), Err(QuotingError::TrailingBackslash));
+    }
+
+    #[test]
+    fn quote_plain_is_untouched() {
+        assert_eq!(quote("/usr/bin/env"), "/usr/bin/env");
+        assert_eq!(quote("--flag=value"), "--flag=value");
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote("a b"), "\"a b\"");
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(&["/bin/echo", "Hello, World!"]);
+        round_trip(&["", " ", "\t", "\n", "\"", "'", "\\", "\\n"]);
+        round_trip(&["sh", "-c", "echo \"$1\" 'x' \\; exit 1", "--"]);
+        round_trip(&["ünïcödé", "tab\tand\nnewline"]);
+        round_trip(&[]);
+    }
+}
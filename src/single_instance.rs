@@ -0,0 +1,68 @@
+/* This file is part of the Aeterno init system. */
+
+/* Single instance handling, shared by -init and -master.
+ *
+ * Each daemon takes an flock(2) on a lock file next to its socket before
+ * binding it, so that a second instance gives up instead of taking the socket
+ * over. A socket that nobody accepts connections on is left behind by an
+ * instance that is gone, and is removed.
+ */
+
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use nix::fcntl::{flock, FlockArg};
+use nix::libc;
+
+/// Takes an exclusive lock on the file at `path`, creating it if needed.
+///
+/// The lock is held for as long as the returned file is open. Fails with
+/// `WouldBlock` if another process holds the lock.
+pub fn acquire_lock(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .custom_flags(libc::O_CLOEXEC)
+        .open(path)?;
+
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(file),
+        Err(nix::Error::Sys(errno)) => {
+            Err(io::Error::from_raw_os_error(errno as i32))
+        },
+        Err(e) => Err(io::Error::other(format!("{:?}", e))),
+    }
+}
+
+/// Removes the socket at `path` if nobody is listening on it anymore.
+///
+/// Fails with `AddrInUse` if the socket is still in use.
+pub fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                    "the socket is in use")),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            fs::remove_file(path)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Takes the lock at `lock_path` and makes room for binding `socket_path`.
+///
+/// Returns the file holding the lock.
+pub fn claim_socket(socket_path: &Path, lock_path: &Path) -> io::Result<File> {
+    let lock = acquire_lock(lock_path)?;
+    remove_stale_socket(socket_path)?;
+
+    Ok(lock)
+}
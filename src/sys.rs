@@ -6,17 +6,21 @@
  */
 
 use std::cell::RefCell;
-use std::os::unix::io::RawFd;
-use std::path::PathBuf;
-use std::process::Command;
-use std::str::from_utf8;
-use std::sync::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 extern crate nix;
-use nix::sys::socket::{accept, listen, MsgFlags, recv};
-use nix::sys::signal::kill;
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::{close, Pid, write};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sched::CloneFlags;
+use nix::sys::socket::{accept4, listen, send, MsgFlags, SockFlag};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::stat::Mode;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{close, getpgrp, Gid, Pid, Uid, write};
 
 #[macro_use]
 extern crate log;
@@ -25,88 +29,341 @@ extern crate env_logger;
 #[macro_use]
 extern crate lazy_static;
 
+extern crate uuid;
+
 // const SYS_SOCKET_PATH: &str = "/run/aeterno/sys.sock";
 const SYS_SOCKET_FD: RawFd = 4;
+/// Lock on the sys socket, held on behalf of aeterno-init
+const SYS_LOCK_FD: RawFd = 5;
 const SYS_SOCKET_BACKLOG: usize = 5;
-const AETERNO_VERSION: &str = "Aeterno 0.0.1 - November 2018\n";
+const AETERNO_VERSION: &str = "Aeterno 0.1.0";
+/// Features listed in reply to `HELO CAPS`, in addition to CGROUP
+const CAPABILITIES: [&str; 13] = [
+    "STOP", "FORCESTOP", "KILL", "KILLGROUP", "FDPASS", "EVENTS2", "LIMIT",
+    "ISOLATION", "LOGS", "SHUTDOWN", "REEXEC", "PGROUP", "UNIT",
+];
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const EVENT_QUEUE_MAX: usize = 256;
+/// Time given to processes to exit on SHUTDOWN, unless the master says
+const SHUTDOWN_GRACE_DEFAULT: Duration = Duration::from_secs(10);
+/// Comma separated lists of users and groups allowed to connect to sys
+const ALLOWED_UIDS_ENV: &str = "AETERNO_SYS_ALLOWED_UIDS";
+const ALLOWED_GIDS_ENV: &str = "AETERNO_SYS_ALLOWED_GIDS";
+/// Error value for mutating commands sent by a connection that is not master
+const ERR_NOT_MASTER: i32 = -2;
+/// Longest command line accepted, excluding the newline
+const SYS_LINE_MAX: usize = 4096;
 
 use std::thread;
 
+#[path = "sys_supervisor.rs"]
+mod supervisor;
+
+mod quoting;
+
+mod fd_passing;
+
+mod line_reader;
+use line_reader::{Line, LineReader};
+
+mod peer_auth;
+use peer_auth::PeerPolicy;
+
+#[path = "sys_proctable.rs"]
+mod proctable;
+
+#[path = "sys_exec.rs"]
+mod exec;
+
+#[path = "sys_isolation.rs"]
+mod isolation;
+
+#[path = "sys_logs.rs"]
+mod logs;
+
+#[path = "sys_cgroup.rs"]
+mod cgroup;
+
+#[path = "sys_shutdown.rs"]
+mod shutdown;
+
+#[path = "sys_signals.rs"]
+mod signals;
+use signals::Signals;
+
+#[path = "sys_reexec.rs"]
+mod reexec;
+
+#[path = "sys_identity.rs"]
+mod identity;
+
+use exec::{Credentials, ExecContext, Grouping, Limit, StdioTarget};
+
 lazy_static! {
     static ref master_fd: Mutex<RefCell<Option<RawFd>>>
         = Mutex::new(RefCell::new(None));
 }
 
+/// Wait events that could not be delivered because no master was connected
+struct EventQueue {
+    pub events: VecDeque<String>,
+    /// Number of events lost to overflow since the last flush
+    pub dropped: u64,
+    /// The rest of a line only partly sent to the master
+    pub tail: Vec<u8>,
+}
+
+lazy_static! {
+    static ref pending_events: Mutex<RefCell<EventQueue>>
+        = Mutex::new(RefCell::new(EventQueue {
+            events: VecDeque::new(),
+            dropped: 0,
+            tail: Vec::new(),
+        }));
+}
+
+lazy_static! {
+    /// Held while the state of sys changes in ways REEXEC hands over, so that
+    /// nothing is lost in between taking the state and execve(2)
+    static ref handover_lock: Mutex<()> = Mutex::new(());
+}
+
+lazy_static! {
+    /// Held while writing to a connection, so that lines written by different
+    /// threads can not end up in the middle of a reply.
+    ///
+    /// Also held while the connection is closed. The entries are kept as the
+    /// FD numbers are reused.
+    static ref conn_writers: Mutex<RefCell<HashMap<RawFd, Arc<Mutex<()>>>>>
+        = Mutex::new(RefCell::new(HashMap::new()));
+}
+
+lazy_static! {
+    /// Processes that will be sent SIGKILL if they outlive their STOP timeout,
+    /// with the time that runs out at
+    static ref stop_watchlist: Mutex<RefCell<Vec<(Pid, Instant)>>>
+        = Mutex::new(RefCell::new(Vec::new()));
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum RawQuery {
-    Helo,
+    Helo(String),
     Bye,
     Master,
     Start(String),
     Stop(String),
+    ForceStop(String),
+    Kill(String),
+    KillGroup(String),
+    KillPg(String),
+    SetEnv(String),
+    ClearEnv,
+    Reset,
+    Chdir(String),
+    User(String),
+    Umask(String),
+    Stdio(StdioTarget, String),
+    Fd(String),
+    Pipe(String),
+    Pidfd,
+    Unit(String),
+    Limit(String),
+    Unshare(String),
+    PrivateTmp,
+    ReadOnly(String),
+    SetSid,
+    SetPgid(String),
+    List,
+    Status(String),
+    Logs(String),
+    Follow(String),
+    Unfollow(String),
+    Shutdown(shutdown::Kind, String),
+    Reexec(String),
     ProtocolError,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 enum Query {
-    Helo,
+    /// Also lists the capabilities if set
+    Helo(bool),
     Bye,
     Master,
     Start(PathBuf, Vec<String>),
-    Stop(Pid),
+    Stop(Pid, Option<Duration>),
+    ForceStop(Pid),
+    Kill(Pid, Signal),
+    KillGroup(String, Signal),
+    KillPg(Pid, Signal),
+    SetEnv(String, String),
+    ClearEnv,
+    Reset,
+    Chdir(PathBuf),
+    User(Credentials),
+    Umask(Mode),
+    Stdio(StdioTarget, PathBuf),
+    Fd(RawFd),
+    Pipe(RawFd),
+    Pidfd,
+    Unit(String),
+    Limit(Limit),
+    Unshare(CloneFlags),
+    PrivateTmp,
+    ReadOnly(PathBuf),
+    SetSid,
+    SetPgid(Pid),
+    List,
+    Status(Pid),
+    Logs(Pid, Option<usize>),
+    Follow(Pid),
+    Unfollow(Pid),
+    Shutdown(shutdown::Kind, Duration),
+    Reexec(Option<PathBuf>),
     ProtocolError,
 }
 
+impl Query {
+    /// Whether the query changes the state of the system, or prepares to.
+    ///
+    /// Only the master connection may issue such queries, all other
+    /// connections are observers.
+    fn is_mutating(&self) -> bool {
+        !matches!(*self,
+                  Query::Helo(_) | Query::Bye | Query::Master |
+                  Query::List | Query::Status(_) |
+                  Query::Logs(_, _) | Query::Follow(_) | Query::Unfollow(_) |
+                  Query::ProtocolError)
+    }
+}
+
+/// The lock held while writing to the connection `conn_fd`
+pub fn conn_writer(conn_fd: RawFd) -> Arc<Mutex<()>> {
+    let writers_cell = conn_writers.lock().unwrap();
+    let mut writers = writers_cell.borrow_mut();
+
+    writers.entry(conn_fd).or_default().clone()
+}
+
+/// Writes all of `data` to `fd`, waiting for room if need be
+fn write_all(fd: RawFd, mut data: &[u8]) -> nix::Result<()> {
+    while !data.is_empty() {
+        match write(fd, data) {
+            Ok(written) => data = &data[written..],
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the whole of `reply` to `conn_fd`, passing `fds` along with it.
+///
+/// Nothing else is written to the connection meanwhile, so a reply of several
+/// lines stays in one piece.
+fn write_reply(conn_fd: RawFd, reply: &str, fds: &[RawFd]) {
+    let writer = conn_writer(conn_fd);
+    let _writing = writer.lock().unwrap();
+
+    /* The master reads while it waits for the reply, let it not cut in */
+    catch_up_events(conn_fd);
+
+    let res = if fds.is_empty() {
+        write_all(conn_fd, reply.as_bytes())
+    } else {
+        fd_passing::send_with_fds(conn_fd, reply.as_bytes(), fds).map(|_| ())
+    };
+    if let Err(e) = res {
+        debug!("cannot reply to FD {}: {:?}", conn_fd, e);
+    }
+
+    /* Events may have been held back while the reply was written */
+    catch_up_events(conn_fd);
+}
+
 macro_rules! conn_ok_with_arg {
     ($fd:expr, $arg:expr) => {
-        {
-            let _ = write($fd, format!("OK {:?}\n", $arg).as_bytes());
-        }
+        write_reply($fd, &format!("OK {:?}\n", $arg), &[])
     }
 }
 
 macro_rules! conn_ok {
     ($fd:expr) => {
-        conn_ok_with_arg!($fd, 0);
+        conn_ok_with_arg!($fd, 0)
     }
 }
 
-macro_rules! conn_err {
-    ($fd:expr, $arg:expr) => {
+macro_rules! conn_ok_with_fds {
+    ($fd:expr, $arg:expr, $fds:expr) => {
         {
-            let _ = write($fd, format!("ERR {:?}\n", $arg).as_bytes());
+            let fds = $fds.iter()
+                .map(|f| f.as_raw_fd())
+                .collect::<Vec<_>>();
+            write_reply($fd, &format!("OK {:?}\n", $arg), &fds);
         }
     }
 }
 
+macro_rules! conn_err {
+    ($fd:expr, $arg:expr) => {
+        write_reply($fd, &format!("ERR {:?}\n", $arg), &[])
+    }
+}
+
 macro_rules! conn_close {
     ($fd:expr) => {
         {
-            /* Remove master */
-            {
+            let writer = conn_writer($fd);
+            let writing = writer.lock().unwrap();
+
+            /* Remove master, if this is the master connection */
+            let was_master = {
                 let master_cell = master_fd.lock().unwrap();
                 let mut master = master_cell.borrow_mut();
 
-                *master = None;
-            }
+                if *master == Some($fd) {
+                    *master = None;
+                    /* The rest of a line means nothing to the next master */
+                    pending_events.lock().unwrap().borrow_mut().tail.clear();
+                    true
+                } else {
+                    false
+                }
+            };
+            logs::unfollow_all($fd);
             let _ = close($fd);
+            drop(writing);
+
+            if was_master {
+                info!("Master connection FD {:?} closed", $fd);
+                supervisor::master_disconnected();
+            }
         }
     }
 }
 
+/// Splits a query into the command and its unparsed arguments
 fn parse_raw_query(s: &str) -> Option<(&str, String)> {
-    let v: Vec<&str> = s.split_whitespace().collect();
-    let cmd = v.get(0)?;
-    let rest = v.get(1..)
-            .map(|x| x.join(" "))
-            .unwrap_or("".to_string());
+    let s = s.trim_start().trim_end_matches(['\r', '\n']);
+    let (cmd, rest) = s.split_once([' ', '\t']).unwrap_or((s, ""));
+
+    if cmd.is_empty() {
+        None
+    } else {
+        Some((cmd, rest.to_string()))
+    }
+}
 
-    Some((cmd, rest))
+/// Counts the arguments of a query, `None` if they can not be parsed
+fn arg_count(args: &str) -> Option<usize> {
+    quoting::split(args)
+        .ok()
+        .map(|v| v.len())
 }
 
 macro_rules! no_arg {
     ($x:expr, $q:expr) => {{
-        if $x == "".to_string() {
+        if arg_count(&$x) == Some(0) {
             $q
         } else {
             RawQuery::ProtocolError
@@ -116,7 +373,17 @@ macro_rules! no_arg {
 
 macro_rules! arg_count_eq {
     ($x:expr, $c:expr, $q:expr) => {{
-            if $x.split_whitespace().count() == $q {
+            if arg_count(&$x) == Some($q) {
+                $c
+            } else {
+                RawQuery::ProtocolError
+            }
+    }}
+}
+
+macro_rules! arg_count_between {
+    ($x:expr, $c:expr, $lo:expr, $hi:expr) => {{
+            if ($lo..=$hi).contains(&arg_count(&$x).unwrap_or(0)) {
                 $c
             } else {
                 RawQuery::ProtocolError
@@ -126,7 +393,7 @@ macro_rules! arg_count_eq {
 
 macro_rules! arg_count_ge {
     ($x:expr, $c:expr, $q:expr) => {{
-            if $x.split_whitespace().count() >= $q {
+            if arg_count(&$x).unwrap_or(0) >= $q {
                 $c
             } else {
                 RawQuery::ProtocolError
@@ -137,59 +404,651 @@ macro_rules! arg_count_ge {
 impl From<&str> for RawQuery {
     fn from(s: &str) -> RawQuery {
         match parse_raw_query(s) {
-            Some(("HELO", x)) => no_arg!(x, RawQuery::Helo),
+            Some(("HELO", x))
+                => arg_count_between!(x, RawQuery::Helo(x), 0, 1),
             Some(("MASTER", x)) => no_arg!(x, RawQuery::Master),
             Some(("START", x)) => arg_count_ge!(x, RawQuery::Start(x), 1),
-            Some(("STOP", x)) => arg_count_eq!(x, RawQuery::Stop(x), 1),
+            Some(("STOP", x))
+                => arg_count_between!(x, RawQuery::Stop(x), 1, 2),
+            Some(("FORCESTOP", x))
+                => arg_count_eq!(x, RawQuery::ForceStop(x), 1),
+            Some(("KILL", x)) => arg_count_eq!(x, RawQuery::Kill(x), 2),
+            Some(("KILLGROUP", x))
+                => arg_count_eq!(x, RawQuery::KillGroup(x), 2),
+            Some(("KILLPG", x)) => arg_count_eq!(x, RawQuery::KillPg(x), 2),
+            Some(("SETENV", x)) => arg_count_ge!(x, RawQuery::SetEnv(x), 1),
+            Some(("CLEARENV", x)) => no_arg!(x, RawQuery::ClearEnv),
+            Some(("RESET", x)) => no_arg!(x, RawQuery::Reset),
+            Some(("CHDIR", x)) => arg_count_eq!(x, RawQuery::Chdir(x), 1),
+            Some(("USER", x)) => arg_count_ge!(x, RawQuery::User(x), 2),
+            Some(("UMASK", x)) => arg_count_eq!(x, RawQuery::Umask(x), 1),
+            Some(("STDIN", x))
+                => arg_count_eq!(x, RawQuery::Stdio(StdioTarget::Stdin, x), 1),
+            Some(("STDOUT", x))
+                => arg_count_eq!(x, RawQuery::Stdio(StdioTarget::Stdout, x), 1),
+            Some(("STDERR", x))
+                => arg_count_eq!(x, RawQuery::Stdio(StdioTarget::Stderr, x), 1),
+            Some(("FD", x)) => arg_count_eq!(x, RawQuery::Fd(x), 1),
+            Some(("PIPE", x)) => arg_count_eq!(x, RawQuery::Pipe(x), 1),
+            Some(("PIDFD", x)) => no_arg!(x, RawQuery::Pidfd),
+            Some(("UNIT", x)) => arg_count_eq!(x, RawQuery::Unit(x), 1),
+            Some(("LIMIT", x))
+                => arg_count_between!(x, RawQuery::Limit(x), 2, 3),
+            Some(("UNSHARE", x)) => arg_count_ge!(x, RawQuery::Unshare(x), 1),
+            Some(("PRIVATETMP", x)) => no_arg!(x, RawQuery::PrivateTmp),
+            Some(("READONLY", x))
+                => arg_count_eq!(x, RawQuery::ReadOnly(x), 1),
+            Some(("SETSID", x)) => no_arg!(x, RawQuery::SetSid),
+            Some(("SETPGID", x))
+                => arg_count_between!(x, RawQuery::SetPgid(x), 0, 1),
+            Some(("LIST", x)) => no_arg!(x, RawQuery::List),
+            Some(("STATUS", x)) => arg_count_eq!(x, RawQuery::Status(x), 1),
+            Some(("LOGS", x))
+                => arg_count_between!(x, RawQuery::Logs(x), 1, 2),
+            Some(("FOLLOW", x)) => arg_count_eq!(x, RawQuery::Follow(x), 1),
+            Some(("UNFOLLOW", x))
+                => arg_count_eq!(x, RawQuery::Unfollow(x), 1),
+            Some(("SHUTDOWN", x)) | Some(("POWEROFF", x))
+                => arg_count_between!(
+                    x, RawQuery::Shutdown(shutdown::Kind::Poweroff, x), 0, 1),
+            Some(("REBOOT", x))
+                => arg_count_between!(
+                    x, RawQuery::Shutdown(shutdown::Kind::Reboot, x), 0, 1),
+            Some(("HALT", x))
+                => arg_count_between!(
+                    x, RawQuery::Shutdown(shutdown::Kind::Halt, x), 0, 1),
+            Some(("REEXEC", x))
+                => arg_count_between!(x, RawQuery::Reexec(x), 0, 1),
             Some(("BYE", x)) => no_arg!(x, RawQuery::Bye),
             _ => RawQuery::ProtocolError,
         }
     }
 }
 
-fn start_process(conn_fd: RawFd, path: PathBuf, args: Vec<String>) {
-    debug!("Starting process {:?} with arguments {:?}", path, args);
+/// Spawns `path` with `args` in the context `ctx`, and starts capturing its
+/// output. Returns the pid, and the descriptors to hand back to the master.
+fn spawn_process(path: &Path, args: &[String], argv: Vec<String>,
+                 ctx: ExecContext) -> io::Result<(Pid, Vec<OwnedFd>)> {
+    let unit = ctx.unit.clone();
+    let (mut cmd, reply_fds) = ctx.into_command(path, args)?;
+
+    let mut child = proctable::spawn(&mut cmd, argv, unit.clone())?;
+    let pid = Pid::from_raw(child.id() as i32);
+
+    logs::capture(pid, unit, child.stdout.take(), child.stderr.take());
+
+    /* Close our copies of the descriptors given to the child */
+    drop(cmd);
+
+    Ok((pid, reply_fds))
+}
+
+fn start_process(conn_fd: RawFd, path: PathBuf, args: Vec<String>,
+                 ctx: ExecContext) {
+    debug!("Starting process {:?} with arguments {:?} in context {:?}",
+           path, args, ctx);
 
-    match Command::new(path).args(args).spawn() {
-        Ok(child) => conn_ok_with_arg!(conn_fd, child.id()),
-        Err(e) => conn_err!(conn_fd, e.raw_os_error().unwrap_or(-1)),
+    let mut argv = vec![path.to_string_lossy().into_owned()];
+    argv.extend(args.iter().cloned());
+
+    let want_pidfd = ctx.pidfd;
+    let unit = ctx.unit.clone();
+
+    /*
+     * Children are not reaped meanwhile, which std relies on to collect a
+     * child that failed to execute, and REEXEC hands over the new process.
+     * Nor is the cgroup of the unit released before the child has joined it.
+     */
+    let res = {
+        let _handover = handover_lock.lock().unwrap();
+        let res = spawn_process(&path, &args, argv, ctx);
+
+        /* Don't leave the cgroup behind for a process that never ran */
+        if let (Err(_), Some(unit)) = (&res, &unit) {
+            cgroup::release(unit);
+        }
+        res
+    };
+
+    let (pid, mut reply_fds) = match res {
+        Ok(started) => started,
+        Err(e) => {
+            conn_err!(conn_fd, e.raw_os_error().unwrap_or(-1));
+            return;
+        },
+    };
+
+    if want_pidfd {
+        match pidfd_open(pid) {
+            Ok(pidfd) => reply_fds.push(pidfd),
+            Err(e) => warn!("failed to open a pidfd for {:?}: {:?}", pid, e),
+        }
+    }
+
+    conn_ok_with_fds!(conn_fd, i32::from(pid), reply_fds);
+}
+
+/// Opens a file descriptor referring to the process `pid`
+fn pidfd_open(pid: Pid) -> nix::Result<OwnedFd> {
+    let fd = unsafe {
+        nix::libc::syscall(nix::libc::SYS_pidfd_open, i32::from(pid), 0)
+    };
+
+    if fd < 0 {
+        Err(nix::Error::last())
+    } else {
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+    }
+}
+
+/// Converts an error from nix into the errno value reported over the protocol
+fn errno_of(e: nix::Error) -> i32 {
+    match e {
+        nix::Error::Sys(errno) => errno as i32,
+        _ => -1,
     }
 }
 
-fn stop_process(conn_fd: RawFd, pid: Pid) {
-    debug!("Stopping process {:?}", pid);
+/// Sends `sig` to `pid`, replying with the errno of kill(2) on failure
+///
+/// Returns whether the signal was delivered.
+fn signal_process(conn_fd: RawFd, pid: Pid, sig: Signal) -> bool {
+    debug!("Sending {:?} to process {:?}", sig, pid);
 
-    conn_ok!(conn_fd);
+    /*
+     * kill(2) treats zero and negative pids as process groups, which would
+     * include aeterno-sys itself. Refuse those as if the process did not exist.
+     */
+    if i32::from(pid) <= 0 {
+        conn_err!(conn_fd, nix::errno::Errno::ESRCH as i32);
+        return false;
+    }
+
+    match kill(pid, sig) {
+        Ok(()) => {
+            conn_ok!(conn_fd);
+            true
+        },
+        Err(e) => {
+            conn_err!(conn_fd, errno_of(e));
+            false
+        },
+    }
 }
 
-fn reply_query(conn_fd: RawFd, q: Query) {
+/// Sends `sig` to the process group `pgid`, replying with the errno of
+/// kill(2) on failure
+fn signal_process_group(conn_fd: RawFd, pgid: Pid, sig: Signal) {
+    debug!("Sending {:?} to process group {:?}", sig, pgid);
+
+    /* Zero and one would hit aeterno-sys itself, or every process */
+    if i32::from(pgid) <= 1 {
+        conn_err!(conn_fd, nix::errno::Errno::ESRCH as i32);
+        return;
+    }
+    if pgid == getpgrp() {
+        conn_err!(conn_fd, nix::errno::Errno::EPERM as i32);
+        return;
+    }
+
+    match kill(Pid::from_raw(-i32::from(pgid)), sig) {
+        Ok(()) => conn_ok!(conn_fd),
+        Err(e) => conn_err!(conn_fd, errno_of(e)),
+    }
+}
+
+/// Removes `pid` from the STOP watchlist, returns whether it was on it
+fn unwatch_stop(pid: Pid) -> bool {
+    let watch_cell = stop_watchlist.lock().unwrap();
+    let mut watchlist = watch_cell.borrow_mut();
+
+    let len = watchlist.len();
+    watchlist.retain(|&(p, _)| p != pid);
+    watchlist.len() != len
+}
+
+/// Waits for `pid` to go away, sending SIGKILL once `deadline` has passed.
+///
+/// The process is considered gone once its wait event has been processed, or
+/// if it no longer exists at all (i.e., it was not our child).
+fn escalate_stop(pid: Pid, deadline: Instant) {
+    loop {
+        let still_watched = {
+            let watch_cell = stop_watchlist.lock().unwrap();
+            let watchlist = watch_cell.borrow();
+
+            watchlist.iter().any(|&(p, _)| p == pid)
+        };
+
+        if !still_watched || kill(pid, None).is_err() {
+            unwatch_stop(pid);
+            debug!("process {:?} exited before its STOP timeout", pid);
+            return;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+
+        thread::sleep(std::cmp::min(STOP_POLL_INTERVAL, deadline - now));
+    }
+
+    if unwatch_stop(pid) {
+        warn!("process {:?} did not stop in time, sending SIGKILL", pid);
+        let _ = kill(pid, Signal::SIGKILL);
+    }
+}
+
+/// Sends SIGKILL to `pid` unless it goes away by `deadline`
+fn watch_stop(pid: Pid, deadline: Instant) {
+    {
+        let _handover = handover_lock.lock().unwrap();
+        let watch_cell = stop_watchlist.lock().unwrap();
+        let mut watchlist = watch_cell.borrow_mut();
+
+        if watchlist.iter().any(|&(p, _)| p == pid) {
+            return;
+        }
+        watchlist.push((pid, deadline));
+    }
+
+    thread::spawn(move || escalate_stop(pid, deadline));
+}
+
+fn stop_process(conn_fd: RawFd, pid: Pid, timeout: Option<Duration>) {
+    debug!("Stopping process {:?} (timeout: {:?})", pid, timeout);
+
+    if let Some(timeout) = timeout {
+        /* Watch before signalling, so that a quick exit is not missed */
+        watch_stop(pid, Instant::now() + timeout);
+
+        if !signal_process(conn_fd, pid, Signal::SIGTERM) {
+            unwatch_stop(pid);
+        }
+    } else {
+        signal_process(conn_fd, pid, Signal::SIGTERM);
+    }
+}
+
+fn force_stop_process(conn_fd: RawFd, pid: Pid) {
+    debug!("Force stopping process {:?}", pid);
+
+    signal_process(conn_fd, pid, Signal::SIGKILL);
+}
+
+/// Brings the machine down in the background, unless that has begun already.
+///
+/// Returns whether the shutdown was started.
+fn begin_shutdown(kind: shutdown::Kind, grace: Duration) -> bool {
+    if !shutdown::begin() {
+        return false;
+    }
+
+    thread::spawn(move || {
+        shutdown::run(kind, grace, &*shutdown::system_backend());
+    });
+    true
+}
+
+/// Replaces sys with the image at `path`, or the one it was started from.
+///
+/// Only returns if that failed, in which case the error is replied. On
+/// success, the new image replies on the master connection.
+fn reexec_sys(conn_fd: RawFd, path: Option<PathBuf>) {
+    let path = path.or_else(|| std::env::args_os().next().map(PathBuf::from));
+    let path = match path {
+        Some(path) => path,
+        None => {
+            conn_err!(conn_fd, nix::errno::Errno::ENOENT as i32);
+            return;
+        },
+    };
+
+    /* Only the master issues REEXEC, it stays the master meanwhile */
+    let writer = conn_writer(conn_fd);
+
+    let e = {
+        let _writing = writer.lock().unwrap();
+        let _handover = handover_lock.lock().unwrap();
+        let master_cell = master_fd.lock().unwrap();
+        let queue_cell = pending_events.lock().unwrap();
+        let mut queue = queue_cell.borrow_mut();
+        let watch_cell = stop_watchlist.lock().unwrap();
+
+        /* The master waits for the reply, which must not cut into a line */
+        if let Some(master) = *master_cell.borrow() {
+            queue.finish_tail(master);
+        }
+
+        let state = reexec::State {
+            master: *master_cell.borrow(),
+            master_pid: supervisor::master_pid(),
+            processes: proctable::list(),
+            streams: logs::open_streams(),
+            logs: logs::history(),
+            events: queue.events.iter().cloned().collect(),
+            dropped: queue.dropped,
+            stops: watch_cell.borrow().iter()
+                .map(|&(pid, deadline)| {
+                    (pid, reexec::monotonic_deadline(deadline))
+                })
+                .collect(),
+        };
+
+        reexec::exec(&path, &state, &[SYS_SOCKET_FD, SYS_LOCK_FD])
+    };
+
+    error!("failed to re-execute aeterno-sys from {:?}: {:?}", path, e);
+    conn_err!(conn_fd, e.raw_os_error().unwrap_or(-1));
+}
+
+/// Picks up what the previous image of sys handed over on REEXEC.
+///
+/// Returns the running master, if there is one.
+fn restore_state(state: reexec::State) -> Option<Pid> {
+    info!("Restoring state from the previous image: {:?}", state);
+
+    /*
+     * Children are not reaped while the state is handed over, so a process
+     * that is gone anyway was not our child, and how it ended is not known.
+     */
+    let (processes, lost): (Vec<_>, Vec<_>) = state.processes.into_iter()
+        .partition(|p| !p.state.is_alive() || kill(p.pid, None).is_ok());
+
+    for &(pid, stream, fd) in &state.streams {
+        let unit = processes.iter()
+            .find(|p| p.pid == pid)
+            .and_then(|p| p.unit.clone());
+        let file = unsafe { std::fs::File::from_raw_fd(fd) };
+
+        match stream {
+            logs::Stream::Stdout => {
+                logs::capture(pid, unit, Some(file), None::<std::fs::File>)
+            },
+            logs::Stream::Stderr => {
+                logs::capture(pid, unit, None::<std::fs::File>, Some(file))
+            },
+        }
+    }
+    logs::adopt(state.logs);
+    proctable::adopt(processes);
+
+    {
+        let queue_cell = pending_events.lock().unwrap();
+        let mut queue = queue_cell.borrow_mut();
+
+        queue.events.extend(state.events);
+        queue.dropped = state.dropped;
+
+        for p in lost {
+            warn!("process {:?} is gone, but did not exit as our child",
+                  p.pid);
+            if let Some(ref unit) = p.unit {
+                cgroup::release(unit);
+            }
+            queue.events.push_back(format!("EVENT LOST {}\n", p.pid));
+        }
+    }
+
+    /* Processes that were being stopped still get SIGKILL in time */
+    for (pid, deadline) in state.stops {
+        watch_stop(pid, reexec::instant_deadline(deadline));
+    }
+
+    if let Some(conn_fd) = state.master {
+        {
+            let master_cell = master_fd.lock().unwrap();
+            *master_cell.borrow_mut() = Some(conn_fd);
+        }
+
+        /* This is the reply to REEXEC */
+        conn_ok!(conn_fd);
+        thread::spawn(move || handle_connection(conn_fd));
+    }
+
+    state.master_pid
+}
+
+/// The version line sent in reply to `HELO`, identifying the system
+fn helo_line() -> String {
+    format!("{} - {} {}\n", AETERNO_VERSION, identity::hostname(),
+            identity::machine_id())
+}
+
+/// The `CAPS` line sent in reply to `HELO CAPS`
+fn capabilities_line() -> String {
+    let mut caps = CAPABILITIES.to_vec();
+    if cgroup::available() {
+        caps.push("CGROUP");
+    }
+
+    format!("CAPS {}\n", caps.join(" "))
+}
+
+/// Whether `conn_fd` is the master connection
+fn is_master(conn_fd: RawFd) -> bool {
+    let master_cell = master_fd.lock().unwrap();
+    let master = master_cell.borrow();
+
+    *master == Some(conn_fd)
+}
+
+/// Replies to a query, returns whether the connection has been closed
+fn reply_query(conn_fd: RawFd, q: Query, ctx: &mut ExecContext,
+               reader: &mut LineReader) -> bool {
     match q {
-        Query::Helo => {
+        Query::Helo(caps) => {
             info!("Received HELO from fd {:?}", conn_fd);
             /*
              * Write version string back to the connection,
              * don't care if it fails
              */
-            let _ = write(conn_fd, AETERNO_VERSION.as_bytes());
+            let mut reply = helo_line();
+            if caps {
+                reply.push_str(&capabilities_line());
+            }
+            write_reply(conn_fd, &reply, &[]);
         },
         Query::Start(path, args) => {
             info!("Received START {:?} command from fd {:?}",
                   path, conn_fd);
 
-            start_process(conn_fd, path, args);
+            /* The context only applies to this START */
+            let ctx = std::mem::take(ctx);
+            if shutdown::in_progress() {
+                conn_err!(conn_fd, nix::errno::Errno::ESHUTDOWN as i32);
+            } else {
+                start_process(conn_fd, path, args, ctx);
+            }
         },
-        Query::Stop(pid) => {
+        Query::Stop(pid, timeout) => {
             info!("Received STOP {:?} command from fd {:?}",
                   pid, conn_fd);
 
-            stop_process(conn_fd, pid);
+            stop_process(conn_fd, pid, timeout);
         },
-        Query::Master => {
-            let master_cell = master_fd.lock().unwrap();
-            let master = master_cell.borrow();
-            let master = master.unwrap();
+        Query::ForceStop(pid) => {
+            info!("Received FORCESTOP {:?} command from fd {:?}",
+                  pid, conn_fd);
+
+            force_stop_process(conn_fd, pid);
+        },
+        Query::Kill(pid, sig) => {
+            info!("Received KILL {:?} {:?} command from fd {:?}",
+                  pid, sig, conn_fd);
+
+            signal_process(conn_fd, pid, sig);
+        },
+        Query::KillGroup(unit, sig) => {
+            info!("Received KILLGROUP {} {:?} command from fd {:?}",
+                  unit, sig, conn_fd);
 
-            if master == conn_fd {
+            match cgroup::kill_all(&unit, sig) {
+                Ok(count) => conn_ok_with_arg!(conn_fd, count),
+                Err(e) => conn_err!(conn_fd, e.raw_os_error().unwrap_or(-1)),
+            }
+        },
+        Query::KillPg(pgid, sig) => {
+            info!("Received KILLPG {:?} {:?} command from fd {:?}",
+                  pgid, sig, conn_fd);
+
+            signal_process_group(conn_fd, pgid, sig);
+        },
+        Query::SetEnv(key, value) => {
+            debug!("Setting {}={:?} for fd {:?}", key, value, conn_fd);
+
+            ctx.env.push((key, value));
+            conn_ok!(conn_fd);
+        },
+        Query::ClearEnv => {
+            ctx.clear_env = true;
+            ctx.env.clear();
+            conn_ok!(conn_fd);
+        },
+        Query::Reset => {
+            *ctx = ExecContext::default();
+            conn_ok!(conn_fd);
+        },
+        Query::Chdir(dir) => {
+            match std::fs::metadata(&dir) {
+                Ok(ref m) if m.is_dir() => {
+                    ctx.cwd = Some(dir);
+                    conn_ok!(conn_fd);
+                },
+                Ok(_) => conn_err!(conn_fd, nix::errno::Errno::ENOTDIR as i32),
+                Err(e) => conn_err!(conn_fd, e.raw_os_error().unwrap_or(-1)),
+            }
+        },
+        Query::User(creds) => {
+            ctx.credentials = Some(creds);
+            conn_ok!(conn_fd);
+        },
+        Query::Umask(mask) => {
+            ctx.umask = Some(mask);
+            conn_ok!(conn_fd);
+        },
+        Query::Stdio(target, path) => {
+            match ctx.set_stdio(target, &path) {
+                Ok(()) => conn_ok!(conn_fd),
+                Err(e) => conn_err!(conn_fd, e.raw_os_error().unwrap_or(-1)),
+            }
+        },
+        Query::Fd(target) => {
+            match reader.take_fd() {
+                Some(fd) => {
+                    ctx.fds.push((fd, target));
+                    conn_ok!(conn_fd);
+                },
+                None => conn_err!(conn_fd, nix::errno::Errno::EBADF as i32),
+            }
+        },
+        Query::Pipe(_) | Query::Pidfd
+                if ctx.reply_fd_count() >= fd_passing::MAX_FDS_PER_MESSAGE => {
+            conn_err!(conn_fd, nix::errno::Errno::EMFILE as i32);
+        },
+        Query::Pipe(target) => {
+            ctx.pipes.push(target);
+            conn_ok!(conn_fd);
+        },
+        Query::Pidfd => {
+            ctx.pidfd = true;
+            conn_ok!(conn_fd);
+        },
+        Query::Unit(uuid) => {
+            ctx.unit = Some(uuid);
+            conn_ok!(conn_fd);
+        },
+        Query::Limit(limit) => {
+            ctx.limits.push(limit);
+            conn_ok!(conn_fd);
+        },
+        Query::Unshare(namespaces) => {
+            ctx.isolation.unshare(namespaces);
+            conn_ok!(conn_fd);
+        },
+        Query::PrivateTmp => {
+            ctx.isolation.set_private_tmp();
+            conn_ok!(conn_fd);
+        },
+        Query::ReadOnly(path) => {
+            match ctx.isolation.add_read_only(path) {
+                Ok(()) => conn_ok!(conn_fd),
+                Err(e) => conn_err!(conn_fd, errno_of(e)),
+            }
+        },
+        Query::SetSid => {
+            ctx.grouping = Some(Grouping::Session);
+            conn_ok!(conn_fd);
+        },
+        Query::SetPgid(pgid) => {
+            ctx.grouping = Some(Grouping::ProcessGroup(pgid));
+            conn_ok!(conn_fd);
+        },
+        Query::List => {
+            let processes = proctable::list();
+
+            let mut reply = format!("OK {}\n", processes.len());
+            for p in processes {
+                reply.push_str(&p.describe());
+            }
+            write_reply(conn_fd, &reply, &[]);
+        },
+        Query::Status(pid) => {
+            match proctable::status(pid) {
+                Some(p) => {
+                    let reply = format!("OK 1\n{}", p.describe());
+                    write_reply(conn_fd, &reply, &[]);
+                },
+                None => conn_err!(conn_fd, nix::errno::Errno::ESRCH as i32),
+            }
+        },
+        Query::Logs(pid, count) => {
+            match logs::tail(pid, count) {
+                Some(lines) => {
+                    let mut reply = format!("OK {}\n", lines.len());
+                    for line in lines {
+                        reply.push_str(&line);
+                    }
+                    write_reply(conn_fd, &reply, &[]);
+                },
+                None => conn_err!(conn_fd, nix::errno::Errno::ESRCH as i32),
+            }
+        },
+        Query::Follow(pid) => {
+            match logs::follow(pid, conn_fd) {
+                Some(true) => conn_ok!(conn_fd),
+                Some(false) => {
+                    let reply = format!("OK 0\n{}", logs::end_of_log(pid));
+                    write_reply(conn_fd, &reply, &[]);
+                },
+                None => conn_err!(conn_fd, nix::errno::Errno::ESRCH as i32),
+            }
+        },
+        Query::Unfollow(pid) => {
+            logs::unfollow(pid, conn_fd);
+            conn_ok!(conn_fd);
+        },
+        Query::Shutdown(kind, grace) => {
+            info!("Received {} command from fd {:?}", kind, conn_fd);
+
+            if begin_shutdown(kind, grace) {
+                conn_ok!(conn_fd);
+            } else {
+                conn_err!(conn_fd, nix::errno::Errno::EALREADY as i32);
+            }
+        },
+        Query::Reexec(path) => {
+            info!("Received REEXEC {:?} command from fd {:?}", path, conn_fd);
+
+            if shutdown::in_progress() {
+                conn_err!(conn_fd, nix::errno::Errno::ESHUTDOWN as i32);
+            } else {
+                reexec_sys(conn_fd, path);
+            }
+        },
+        Query::Master => {
+            if is_master(conn_fd) {
                 info!("Connection {:?} is master", conn_fd);
                 conn_ok!(conn_fd);
             } else {
@@ -199,64 +1058,522 @@ fn reply_query(conn_fd: RawFd, q: Query) {
         },
         Query::ProtocolError => {
             info!("Protocol error with fd {:?}", conn_fd);
+            conn_err!(conn_fd, -1);
         },
         Query::Bye => {
             conn_close!(conn_fd);
+            return true;
         },
     }
+
+    false
+}
+
+/// Parses a process identifier argument.
+///
+/// Whether the process exists is left to kill(2), so that its errno can be
+/// reported back to the master.
+fn parse_pid(s: &str) -> Option<Pid> {
+    s.parse::<i32>()
+        .ok()
+        .map(Pid::from_raw)
+}
+
+/// Parses a signal given by its number
+fn parse_signal(s: &str) -> Option<Signal> {
+    s.parse::<i32>()
+        .ok()
+        .and_then(|s| Signal::from_c_int(s).ok())
+}
+
+/// Parses a resource limit, `Some(None)` standing for `infinity`
+fn parse_limit(s: &str) -> Option<Option<u64>> {
+    match s {
+        "infinity" => Some(None),
+        n => n.parse().ok().map(Some),
+    }
+}
+
+/// Parses the number a file descriptor is to be installed as in a new process
+fn parse_target_fd(s: &str) -> Option<RawFd> {
+    s.parse::<RawFd>()
+        .ok()
+        .filter(|fd| *fd >= 0)
+}
+
+/// Parses arguments that consist of exactly one (possibly quoted) argument
+fn single_arg(s: &str) -> Option<String> {
+    let mut args = quoting::split(s).ok()?;
+
+    if args.len() == 1 {
+        args.pop()
+    } else {
+        None
+    }
 }
 
 /* TODO: convert this to a Result type */
 fn validate_raw_query(rq: RawQuery) -> Option<Query> {
     match rq {
-        RawQuery::Helo => Some(Query::Helo),
+        RawQuery::Helo(caps) => match caps.trim() {
+            "" => Some(Query::Helo(false)),
+            "CAPS" => Some(Query::Helo(true)),
+            _ => Some(Query::ProtocolError),
+        },
         RawQuery::Bye => Some(Query::Bye),
         RawQuery::Master => Some(Query::Master),
         RawQuery::ProtocolError => Some(Query::ProtocolError),
         RawQuery::Start(path_str) => {
             let mut p = PathBuf::new();
-            let path_exploded = path_str.split_whitespace()
-                .map(str::to_string)
-                .collect::<Vec<String>>();
-            p.push(path_exploded[0].clone());
-
-            /* Verify that the path is valid */
-            if p.exists() {
-                Some(Query::Start(p, path_exploded[1..].into()))
+            let path_exploded = quoting::split(&path_str).ok()?;
+            p.push(path_exploded.first()?);
+
+            /* Whether the path is valid is reported by execve(2) */
+            Some(Query::Start(p, path_exploded[1..].into()))
+        },
+        RawQuery::SetEnv(assignment) => {
+            let assignment = single_arg(&assignment)?;
+            let (key, value) = assignment.split_once('=')?;
+
+            if key.is_empty() || key.contains(char::is_whitespace) {
+                return None;
+            }
+
+            Some(Query::SetEnv(key.to_string(), value.to_string()))
+        },
+        RawQuery::ClearEnv => Some(Query::ClearEnv),
+        RawQuery::Reset => Some(Query::Reset),
+        RawQuery::Chdir(dir) => {
+            Some(Query::Chdir(PathBuf::from(single_arg(&dir)?)))
+        },
+        RawQuery::User(ids) => {
+            let mut ids = ids.split_whitespace()
+                .map(|id| id.parse::<u32>().ok());
+            let uid = Uid::from_raw(ids.next()??);
+            let gid = Gid::from_raw(ids.next()??);
+            let groups = ids.map(|g| g.map(Gid::from_raw))
+                .collect::<Option<Vec<Gid>>>()?;
+
+            Some(Query::User(Credentials {
+                uid,
+                gid,
+                groups,
+            }))
+        },
+        RawQuery::Umask(mask) => {
+            u32::from_str_radix(&mask, 8)
+                .ok()
+                .and_then(Mode::from_bits)
+                .map(Query::Umask)
+        },
+        RawQuery::Stdio(target, path) => {
+            Some(Query::Stdio(target, PathBuf::from(single_arg(&path)?)))
+        },
+        RawQuery::Stop(args) => {
+            let mut args = args.split_whitespace();
+            let pid = parse_pid(args.next()?)?;
+            let timeout = match args.next() {
+                Some(ms) => Some(Duration::from_millis(ms.parse().ok()?)),
+                None => None,
+            };
+
+            Some(Query::Stop(pid, timeout))
+        },
+        RawQuery::Fd(target) => parse_target_fd(&target).map(Query::Fd),
+        RawQuery::Pipe(target) => parse_target_fd(&target).map(Query::Pipe),
+        RawQuery::Pidfd => Some(Query::Pidfd),
+        RawQuery::Unit(uuid) => {
+            single_arg(&uuid)
+                .filter(|u| cgroup::is_valid_name(u))
+                .map(Query::Unit)
+        },
+        RawQuery::Limit(args) => {
+            let mut args = args.split_whitespace();
+            let name = args.next()?;
+            let soft = parse_limit(args.next()?)?;
+            let hard = match args.next() {
+                Some(hard) => parse_limit(hard)?,
+                None => soft,
+            };
+
+            Limit::new(name, soft, hard).map(Query::Limit)
+        },
+        RawQuery::Unshare(names) => {
+            names.split_whitespace()
+                .map(isolation::parse_namespace)
+                .collect::<Option<Vec<_>>>()
+                .map(|ns| {
+                    ns.into_iter().fold(CloneFlags::empty(), |a, b| a | b)
+                })
+                .map(Query::Unshare)
+        },
+        RawQuery::PrivateTmp => Some(Query::PrivateTmp),
+        RawQuery::SetSid => Some(Query::SetSid),
+        RawQuery::SetPgid(pgid) => {
+            /* Without an argument, the process leads a group of its own */
+            if pgid.trim().is_empty() {
+                Some(Query::SetPgid(Pid::from_raw(0)))
             } else {
-                None
+                parse_pid(&single_arg(&pgid)?)
+                    .filter(|&pgid| i32::from(pgid) > 0)
+                    .map(Query::SetPgid)
             }
         },
-        RawQuery::Stop(pid_str) => {
-            let pid_i = pid_str
-                .parse::<i32>()
-                .ok();
-            if let Some(p) = pid_i {
-                let pid = Pid::from_raw(p);
+        RawQuery::ReadOnly(path) => {
+            Some(Query::ReadOnly(PathBuf::from(single_arg(&path)?)))
+        },
+        RawQuery::List => Some(Query::List),
+        RawQuery::Status(pid_str) => parse_pid(&pid_str).map(Query::Status),
+        RawQuery::Logs(args) => {
+            let mut args = args.split_whitespace();
+            let pid = parse_pid(args.next()?)?;
+            let count = match args.next() {
+                Some(count) => Some(count.parse().ok()?),
+                None => None,
+            };
 
-                /* Verify that the PID is valid */
-                if kill(pid, None).is_ok() {
-                    Some(Query::Stop(pid))
-                } else {
-                    None
-                }
+            Some(Query::Logs(pid, count))
+        },
+        RawQuery::Follow(pid_str) => parse_pid(&pid_str).map(Query::Follow),
+        RawQuery::Unfollow(pid_str) => {
+            parse_pid(&pid_str).map(Query::Unfollow)
+        },
+        RawQuery::Shutdown(kind, grace) => {
+            let grace = match grace.trim() {
+                "" => SHUTDOWN_GRACE_DEFAULT,
+                ms => Duration::from_millis(ms.parse().ok()?),
+            };
+
+            Some(Query::Shutdown(kind, grace))
+        },
+        RawQuery::Reexec(path) => {
+            if arg_count(&path)? == 0 {
+                Some(Query::Reexec(None))
             } else {
-                None
+                Some(Query::Reexec(Some(PathBuf::from(single_arg(&path)?))))
+            }
+        },
+        RawQuery::ForceStop(pid_str) => {
+            parse_pid(&pid_str).map(Query::ForceStop)
+        },
+        RawQuery::Kill(args) => {
+            let mut args = args.split_whitespace();
+            let pid = parse_pid(args.next()?)?;
+            let sig = parse_signal(args.next()?)?;
+
+            Some(Query::Kill(pid, sig))
+        },
+        RawQuery::KillGroup(args) => {
+            let mut args = args.split_whitespace();
+            let unit = args.next()?;
+            let sig = parse_signal(args.next()?)?;
+
+            if !cgroup::is_valid_name(unit) {
+                return None;
             }
+
+            Some(Query::KillGroup(unit.to_string(), sig))
+        },
+        RawQuery::KillPg(args) => {
+            let mut args = args.split_whitespace();
+            let pgid = parse_pid(args.next()?)?;
+            let sig = parse_signal(args.next()?)?;
+
+            Some(Query::KillPg(pgid, sig))
         },
     }
 }
 
+/// Formats a wait event into its protocol representation.
+///
+/// Returns `None` for wait statuses that are not reported to the master.
+fn format_wait_event(wait: &WaitStatus) -> Option<String> {
+    match *wait {
+        WaitStatus::Exited(pid, code) =>
+            Some(format!("EVENT EXITED {} {}\n", pid, code)),
+        WaitStatus::Signaled(pid, sig, core) =>
+            Some(format!("EVENT SIGNALED {} {} {}\n",
+                         pid, sig as i32, core as i32)),
+        WaitStatus::Stopped(pid, sig) =>
+            Some(format!("EVENT STOPPED {} {}\n", pid, sig as i32)),
+        WaitStatus::Continued(pid) =>
+            Some(format!("EVENT CONTINUED {}\n", pid)),
+        _ => None,
+    }
+}
+
+/// Must be called with `handover_lock` held since the waitpid(2) that returned
+/// `wait`, or a REEXEC in between would lose the event
 fn process_wait_event(wait: WaitStatus) {
+    /* A process that exited no longer needs to be force stopped */
+    match wait {
+        WaitStatus::Exited(pid, _) | WaitStatus::Signaled(pid, _, _) => {
+            unwatch_stop(pid);
+            supervisor::process_exited(pid);
+        },
+        _ => (),
+    }
+    if let Some(unit) = proctable::update(&wait).and_then(|p| p.unit) {
+        cgroup::release(&unit);
+    }
+
+    match format_wait_event(&wait) {
+        Some(event) => send_event(event),
+        None => debug!("ignoring wait event {:?}", wait),
+    }
+}
+
+/// Sends as much of `data` to the socket `fd` as there is room for, without
+/// blocking. Returns how much that was.
+fn send_nonblocking(fd: RawFd, data: &[u8]) -> nix::Result<usize> {
+    match send(fd, data, MsgFlags::MSG_DONTWAIT) {
+        Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => Ok(0),
+        res => res,
+    }
+}
+
+impl EventQueue {
+    /// Starts sending `line` to the master connection `conn_fd`, without
+    /// blocking. Returns whether the line was taken.
+    ///
+    /// The part of the line the master has no room for is kept as the tail,
+    /// which must go out before anything else is written to the connection.
+    fn send_line(&mut self, conn_fd: RawFd, line: &str) -> bool {
+        match send_nonblocking(conn_fd, line.as_bytes()) {
+            Ok(0) => false,
+            Ok(sent) => {
+                self.tail.extend_from_slice(&line.as_bytes()[sent..]);
+                true
+            },
+            Err(e) => {
+                warn!("cannot send {:?} to the master: {:?}", line, e);
+                false
+            },
+        }
+    }
+
+    /// Sends what is left of a line the master had no room for, without
+    /// blocking. Returns whether all of it went out.
+    fn send_tail(&mut self, conn_fd: RawFd) -> bool {
+        if !self.tail.is_empty() {
+            if let Ok(sent) = send_nonblocking(conn_fd, &self.tail) {
+                self.tail.drain(..sent);
+            }
+        }
+
+        self.tail.is_empty()
+    }
+
+    /// Writes what is left of a line the master had no room for, waiting for
+    /// the master to read it if need be
+    fn finish_tail(&mut self, conn_fd: RawFd) {
+        while !self.tail.is_empty() {
+            match write(conn_fd, &self.tail) {
+                Ok(written) => {
+                    self.tail.drain(..written);
+                },
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(e) => {
+                    warn!("cannot send the rest of an event to the master: \
+                           {:?}", e);
+                    self.tail.clear();
+                },
+            }
+        }
+    }
+}
+
+/// Sends an event to the master, or queues it if there is no master.
+///
+/// A master that does not read its events must not hold up the main loop, so
+/// events it has no room for are queued as well, and sent along with the
+/// next one.
+fn send_event(event: String) {
     let master_cell = master_fd.lock().unwrap();
     let master = master_cell.borrow();
 
-    if master.is_none() {
-        warn!("wait event ({:?}) without master!", wait);
-    } else {
-        let master = master.unwrap();
-        let _ = write(master, format!("{:?}\n", wait).as_bytes());
-        debug!("processed wait event {:?}", wait);
+    if let Some(master) = *master {
+        /*
+         * Another thread writing to the master sends the queued events once
+         * it is done, waiting for it here could deadlock
+         */
+        let writer = conn_writer(master);
+        let writing = writer.try_lock();
+
+        /* Earlier events that are still queued go first */
+        if writing.is_ok() && flush_events(master) {
+            let queue_cell = pending_events.lock().unwrap();
+            let mut queue = queue_cell.borrow_mut();
+
+            if queue.send_line(master, &event) {
+                debug!("sent event {:?}", event);
+                return;
+            }
+        }
+
+        if writing.is_ok() {
+            warn!("master is not reading, queueing event {:?}", event);
+        }
+        queue_event(event);
+        return;
+    }
+
+    warn!("event {:?} without master, queueing it", event);
+    queue_event(event);
+}
+
+/// Keeps an undelivered event around until the next master connects.
+///
+/// Must be called with `master_fd` locked, so that it can not race with
+/// `flush_events`.
+fn queue_event(event: String) {
+    let queue_cell = pending_events.lock().unwrap();
+    let mut queue = queue_cell.borrow_mut();
+
+    if queue.events.len() >= EVENT_QUEUE_MAX {
+        /* Make room by dropping the oldest event */
+        queue.events.pop_front();
+        queue.dropped += 1;
+        warn!("event queue full, {} event(s) dropped so far", queue.dropped);
+    }
+
+    queue.events.push_back(event);
+}
+
+/// Delivers the queued events to the master connection `conn_fd`, as far as
+/// it has room for them. Returns whether everything went out.
+///
+/// Must be called with the writer of `conn_fd` held, and `master_fd` locked so
+/// that no new events are written to the connection before the queued ones.
+fn flush_events(conn_fd: RawFd) -> bool {
+    let queue_cell = pending_events.lock().unwrap();
+    let mut queue = queue_cell.borrow_mut();
+
+    if !queue.send_tail(conn_fd) {
+        return false;
+    }
+
+    if queue.dropped > 0 {
+        let line = format!("EVENT DROPPED {}\n", queue.dropped);
+        if !queue.send_line(conn_fd, &line) {
+            return false;
+        }
+        queue.dropped = 0;
+    }
+
+    if !queue.events.is_empty() {
+        info!("Replaying {} queued event(s) to FD {:?}",
+              queue.events.len(), conn_fd);
+    }
+
+    while queue.tail.is_empty() {
+        let event = match queue.events.pop_front() {
+            Some(event) => event,
+            None => return true,
+        };
+
+        if !queue.send_line(conn_fd, &event) {
+            queue.events.push_front(event);
+            return false;
+        }
+    }
+
+    false
+}
+
+/// Finishes the event line the master was in the middle of and sends the
+/// queued events, if `conn_fd` is the master connection.
+///
+/// Must be called with the writer of `conn_fd` held.
+fn catch_up_events(conn_fd: RawFd) {
+    let master_cell = master_fd.lock().unwrap();
+
+    if *master_cell.borrow() == Some(conn_fd) {
+        {
+            let queue_cell = pending_events.lock().unwrap();
+            queue_cell.borrow_mut().finish_tail(conn_fd);
+        }
+
+        flush_events(conn_fd);
+    }
+}
+
+/// Sends the events that were queued while the master was not reading, if
+/// `conn_fd` is the master connection
+fn resume_events(conn_fd: RawFd) {
+    let writer = conn_writer(conn_fd);
+    let _writing = writer.lock().unwrap();
+
+    catch_up_events(conn_fd);
+}
+
+/// Sends the events queued while another thread was writing to `conn_fd`, as
+/// far as the master has room for them.
+///
+/// Must be called with the writer of `conn_fd` held.
+pub fn send_held_events(conn_fd: RawFd) {
+    let master_cell = master_fd.lock().unwrap();
+
+    if *master_cell.borrow() == Some(conn_fd) {
+        flush_events(conn_fd);
+    }
+}
+
+/// Reaps every child of sys that has changed state
+fn reap_children() {
+    loop {
+        let _handover = handover_lock.lock().unwrap();
+        let res = waitpid(None, Some(WaitPidFlag::WNOHANG |
+                                     WaitPidFlag::WUNTRACED |
+                                     WaitPidFlag::WCONTINUED));
+        match res {
+            Ok(WaitStatus::StillAlive) | Err(_) => return,
+            Ok(wait) => process_wait_event(wait),
+        }
+    }
+}
+
+/// Logs what sys knows about, on request of SIGUSR1
+fn dump_state() {
+    let master = {
+        let master_cell = master_fd.lock().unwrap();
+        let master = *master_cell.borrow();
+        master
+    };
+    let queued = {
+        let queue_cell = pending_events.lock().unwrap();
+        let queue = queue_cell.borrow();
+        (queue.events.len(), queue.dropped)
+    };
+
+    info!("State: master connection {:?}, {} queued event(s), {} dropped, \
+           shutting down: {}", master, queued.0, queued.1,
+          shutdown::in_progress());
+    for p in proctable::list() {
+        info!("State: {}", p.describe().trim_end());
+    }
+}
+
+fn handle_signal(sig: Signal, action: signals::Action) {
+    info!("Received {:?}, action: {:?}", sig, action);
+
+    let kind = match action {
+        signals::Action::Ignore => return,
+        signals::Action::Notify => {
+            send_event(format!("EVENT SIGNAL {}\n", sig as i32));
+            return;
+        },
+        signals::Action::Dump => {
+            dump_state();
+            return;
+        },
+        signals::Action::Poweroff => shutdown::Kind::Poweroff,
+        signals::Action::Reboot => shutdown::Kind::Reboot,
+        signals::Action::Halt => shutdown::Kind::Halt,
+    };
+
+    if !begin_shutdown(kind, SHUTDOWN_GRACE_DEFAULT) {
+        info!("Already going down, ignoring {:?}", sig);
     }
 }
 
@@ -265,6 +1582,8 @@ fn handle_connection(conn_fd: RawFd) {
 
     /* check if we need to 'masterize' this connection */
     {
+        let writer = conn_writer(conn_fd);
+        let _writing = writer.lock().unwrap();
         let master_cell = master_fd.lock().unwrap();
         let mut master = master_cell.borrow_mut();
 
@@ -272,52 +1591,72 @@ fn handle_connection(conn_fd: RawFd) {
             /* Yes, this fd becomes the master */
             *master = Some(conn_fd);
             info!("Connection FD {:?} became master", conn_fd);
+
+            flush_events(conn_fd);
         }
 
         /* master is dropped here */
     }
 
-    /* Read in command from the connection */
+    /* Context for the next START on this connection */
+    let mut ctx = ExecContext::default();
+
+    /* Read in commands from the connection, one per line */
+    let mut reader = LineReader::new(conn_fd, SYS_LINE_MAX);
     loop {
-        let buf: &mut [u8] = &mut [0; 256];
-        let size = recv(conn_fd, buf, MsgFlags::empty());
-        if size.is_ok() {
-            /* The .unwrap() here is OK, since we check is_ok() before */
-            let size = size.unwrap();
-            if size == 0 {
+        let query = match reader.read_line() {
+            Ok(Some(Line::Text(line))) => {
+                debug!("Received {:?} from FD {}", line, conn_fd);
+                RawQuery::from(line.as_str())
+            },
+            Ok(Some(Line::TooLong)) => {
+                warn!("Oversized command from FD {}", conn_fd);
+                conn_err!(conn_fd, nix::errno::Errno::E2BIG as i32);
+                continue;
+            },
+            Ok(Some(Line::NotUtf8)) => RawQuery::ProtocolError,
+            Ok(None) => {
                 debug!("Connection terminated with FD {}", conn_fd);
                 conn_close!(conn_fd);
                 break;
+            },
+            Err(e) => {
+                debug!("Failed to receive from FD {}: {:?}", conn_fd, e);
+                conn_close!(conn_fd);
+                break;
+            },
+        };
+
+        /* The master reads its events while it waits for the reply */
+        resume_events(conn_fd);
+
+        if let Some(q) = validate_raw_query(query) {
+            if q.is_mutating() && !is_master(conn_fd) {
+                info!("Refusing {:?} from observer FD {}", q, conn_fd);
+                conn_err!(conn_fd, ERR_NOT_MASTER);
+                continue;
             }
-            debug!("Received {} bytes", size);
-            let query = from_utf8(&buf)
-                .map(|str| { str.trim_matches(char::from(0)) })
-                .map(From::from)
-                .unwrap_or_else(|err| {
-                    debug!("{:?}", err);
-                    RawQuery::ProtocolError
-                });
-
-            if let Some(q) = validate_raw_query(query) {
-                reply_query(conn_fd, q);
-            } else {
-                conn_err!(conn_fd, -1);
+
+            if reply_query(conn_fd, q, &mut ctx, &mut reader) {
+                break;
             }
         } else {
-            /* TODO: emit error (if there's an error) */
-            // debug!("Failed to receive from socket");
-            break;
+            conn_err!(conn_fd, -1);
         }
     }
 }
 
-fn socket_listener() {
-    listen(SYS_SOCKET_FD, SYS_SOCKET_BACKLOG)
-        .expect("FATAL: cannot listen on the Aeterno socket.");
-
+fn socket_listener(policy: PeerPolicy) {
     loop {
-        if let Ok(conn_fd) = accept(SYS_SOCKET_FD) {
+        /* Connections must not leak into the processes we start */
+        if let Ok(conn_fd) = accept4(SYS_SOCKET_FD, SockFlag::SOCK_CLOEXEC) {
             debug!("Accepted a connection with FD {}", conn_fd);
+
+            if policy.authenticate(conn_fd).is_none() {
+                let _ = close(conn_fd);
+                continue;
+            }
+
             let r = thread::spawn(move || {
                 handle_connection(conn_fd);
             });
@@ -331,17 +1670,81 @@ fn main() {
     /* Initialize logging */
     env_logger::init();
 
-    /* TODO: spawn aeterno-master */
+    /* Before any thread is spawned, so that they all block the signals */
+    let mut signals = Signals::setup()
+        .expect("FATAL: cannot set up the signalfd.");
+
+    /* HELO reports these, so before accepting connections */
+    identity::init();
 
     /* Start the socket listener */
-    thread::spawn(socket_listener);
+    fcntl(SYS_SOCKET_FD, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+        .expect("FATAL: cannot set close-on-exec on the Aeterno socket.");
+    listen(SYS_SOCKET_FD, SYS_SOCKET_BACKLOG)
+        .expect("FATAL: cannot listen on the Aeterno socket.");
+
+    /* The lock must not be inherited, or it would outlive us */
+    if fcntl(SYS_LOCK_FD, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).is_err() {
+        warn!("no lock on the Aeterno socket, not started by aeterno-init?");
+    }
+
+    /*
+     * After REEXEC, carry on where the previous image left off. This comes
+     * before accepting connections, so that none of them can claim to be
+     * master in the meantime.
+     */
+    let master = reexec::take_state().and_then(restore_state);
+
+    let policy = PeerPolicy::from_env(ALLOWED_UIDS_ENV, ALLOWED_GIDS_ENV);
+    info!("Peer policy: {:?}", policy);
+    thread::spawn(move || socket_listener(policy));
+
+    cgroup::init();
+
+    /* The socket is ready, so aeterno-master can be spawned now */
+    supervisor::start(master);
+
+    /* Children may have exited while we were being replaced */
+    reap_children();
 
     /* The main thread should forever yield */
     loop {
-        let res = waitpid(None, None);
-        match res {
-            Err(_) => (),
-            Ok(wait) => process_wait_event(wait),
+        match signals.wait() {
+            Some(Signal::SIGCHLD) => reap_children(),
+            Some(sig) => handle_signal(sig, signals.action(sig)),
+            None => (),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(line: &str) -> Option<Query> {
+        validate_raw_query(RawQuery::from(line))
+    }
+
+    #[test]
+    fn validates_setpgid() {
+        assert_eq!(validate("SETPGID\n"),
+                   Some(Query::SetPgid(Pid::from_raw(0))));
+        assert_eq!(validate("SETPGID 42\n"),
+                   Some(Query::SetPgid(Pid::from_raw(42))));
+        assert_eq!(validate("SETPGID 0\n"), None);
+        assert_eq!(validate("SETPGID -42\n"), None);
+        assert_eq!(validate("SETPGID group\n"), None);
+        assert_eq!(validate("SETPGID 42 43\n"), Some(Query::ProtocolError));
+    }
+
+    #[test]
+    fn validates_killpg() {
+        assert_eq!(validate("KILLPG 42 15\n"),
+                   Some(Query::KillPg(Pid::from_raw(42), Signal::SIGTERM)));
+        assert_eq!(validate("KILLPG 42 999\n"), None);
+        assert_eq!(validate("KILLPG 42 TERM\n"), None);
+        assert_eq!(validate("KILLPG group 15\n"), None);
+        assert_eq!(validate("KILLPG 42\n"), Some(Query::ProtocolError));
+        assert_eq!(validate("KILLPG 42 15 9\n"), Some(Query::ProtocolError));
+    }
+}
@@ -0,0 +1,133 @@
+/* This file is part of the Aeterno init system. */
+
+/* Goal:
+ *  - Place every process started for a unit into a cgroup of its own, so that
+ *    processes that double-fork can still be found
+ *  - Signal every process of a unit through KILLGROUP
+ *
+ * Only the unified (v2) hierarchy is supported. The cgroup of a unit is a
+ * directory named after its uuid, below a root that can be configured.
+ */
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+/// Environment variable overriding the root of the aeterno cgroups
+const CGROUP_ROOT_ENV: &str = "AETERNO_CGROUP_ROOT";
+const CGROUP_ROOT_DEFAULT: &str = "/sys/fs/cgroup/aeterno";
+
+lazy_static! {
+    static ref cgroup_root: PathBuf = env::var_os(CGROUP_ROOT_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(CGROUP_ROOT_DEFAULT));
+}
+
+/// Whether `unit` can safely be used as the name of a cgroup
+pub fn is_valid_name(unit: &str) -> bool {
+    !unit.is_empty()
+        && unit.chars().all(|c| c.is_ascii_alphanumeric() || "-_".contains(c))
+}
+
+fn path_of(unit: &str) -> PathBuf {
+    cgroup_root.join(unit)
+}
+
+/// Creates the root of the aeterno cgroups, if it does not exist yet
+pub fn init() {
+    match fs::create_dir_all(&*cgroup_root) {
+        Ok(()) => info!("Placing units into cgroups below {:?}", *cgroup_root),
+        Err(e) => warn!("cannot create the cgroup root {:?}, units will not \
+                         be placed into cgroups: {:?}", *cgroup_root, e),
+    }
+}
+
+/// Whether units are placed into cgroups
+pub fn available() -> bool {
+    cgroup_root.join("cgroup.procs").is_file()
+}
+
+/// Opens the process list of the cgroup of `unit`, creating the cgroup first.
+///
+/// Writing `0` to the file moves the writing process into the cgroup. Returns
+/// `None` if units are not placed into cgroups at all.
+///
+/// The cgroup is removed by `release` once it is empty, so the two must not
+/// run concurrently, and the file must be written to before `release` runs.
+pub fn procs_file(unit: &str) -> io::Result<Option<File>> {
+    if !available() {
+        return Ok(None);
+    }
+
+    let path = path_of(unit);
+    let res = fs::create_dir(&path)
+        .or_else(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => Ok(()),
+            _ => Err(e),
+        })
+        .and_then(|()| {
+            OpenOptions::new()
+                .write(true)
+                .open(path.join("cgroup.procs"))
+        });
+
+    match res {
+        Ok(file) => Ok(Some(file)),
+        Err(e) => {
+            warn!("cannot set up cgroup {:?}: {:?}", path, e);
+            Err(e)
+        },
+    }
+}
+
+/// Removes the cgroup of `unit` if no process is left in it.
+///
+/// See `procs_file` on running both at the same time.
+pub fn release(unit: &str) {
+    /* rmdir(2) fails with EBUSY while the cgroup is populated */
+    if fs::remove_dir(path_of(unit)).is_ok() {
+        debug!("removed the cgroup of unit {}", unit);
+    }
+}
+
+/// Lists the processes in the cgroup of `unit`
+fn processes(unit: &str) -> io::Result<Vec<Pid>> {
+    let procs = fs::read_to_string(path_of(unit).join("cgroup.procs"))?;
+
+    Ok(procs.lines()
+       .filter_map(|pid| pid.trim().parse().ok())
+       .map(Pid::from_raw)
+       .collect())
+}
+
+/// Sends `sig` to every process in the cgroup of `unit`.
+///
+/// Returns the number of processes that were found in the cgroup.
+pub fn kill_all(unit: &str, sig: Signal) -> io::Result<usize> {
+    let pids = processes(unit)?;
+
+    for pid in &pids {
+        /* The process may have exited in the meantime */
+        let _ = kill(*pid, sig);
+    }
+
+    /*
+     * Processes forked after the list was read would escape, so let the
+     * kernel take care of SIGKILL where it can.
+     */
+    if sig == Signal::SIGKILL {
+        let killed = OpenOptions::new()
+            .write(true)
+            .open(path_of(unit).join("cgroup.kill"))
+            .and_then(|mut f| f.write_all(b"1"));
+        if let Err(e) = killed {
+            debug!("cgroup.kill unavailable for unit {}: {:?}", unit, e);
+        }
+    }
+
+    Ok(pids.len())
+}
@@ -0,0 +1,309 @@
+/* This file is part of the Aeterno init system. */
+
+/* Goal:
+ *  - Collect the execution context sent ahead of a START command
+ *  - Apply it to the process being started, and only to that one
+ */
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::libc;
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{dup2, getegid, geteuid, Gid, Pid, pipe2, setgid, setgroups};
+use nix::unistd::{setpgid, setsid, setuid, Uid, write};
+
+use identity;
+use isolation::Isolation;
+
+/// Mode of files created by `STDOUT`/`STDERR`
+const STDIO_FILE_MODE: u32 = 0o644;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StdioTarget {
+    Stdin,
+    Stdout,
+    Stderr,
+}
+
+#[cfg(target_env = "gnu")]
+type RawResource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+type RawResource = libc::c_int;
+
+/// A resource limit set with `LIMIT`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Limit {
+    resource: RawResource,
+    soft: libc::rlim_t,
+    hard: libc::rlim_t,
+}
+
+impl Limit {
+    /// Creates the limit of the resource called `name` as in `RLIMIT_<name>`,
+    /// where `None` stands for no limit.
+    pub fn new(name: &str, soft: Option<u64>, hard: Option<u64>)
+            -> Option<Limit> {
+        let resource = match name {
+            "NOFILE" => libc::RLIMIT_NOFILE,
+            "NPROC" => libc::RLIMIT_NPROC,
+            "CORE" => libc::RLIMIT_CORE,
+            "AS" => libc::RLIMIT_AS,
+            "MEMLOCK" => libc::RLIMIT_MEMLOCK,
+            "STACK" => libc::RLIMIT_STACK,
+            "CPU" => libc::RLIMIT_CPU,
+            _ => return None,
+        };
+        let soft = soft.unwrap_or(libc::RLIM_INFINITY);
+        let hard = hard.unwrap_or(libc::RLIM_INFINITY);
+
+        if soft > hard {
+            return None;
+        }
+
+        Some(Limit {
+            resource,
+            soft,
+            hard,
+        })
+    }
+}
+
+/// How the new process leaves the session and process group of sys
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Grouping {
+    /// A new session, set with `SETSID`
+    Session,
+    /// The given process group, or a new one if zero, set with `SETPGID`
+    ProcessGroup(Pid),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Credentials {
+    pub uid: Uid,
+    pub gid: Gid,
+    /// Supplementary groups
+    pub groups: Vec<Gid>,
+}
+
+/// Everything set up by the context commands since the last START
+#[derive(Debug, Default)]
+pub struct ExecContext {
+    /// Start with an empty environment instead of the one of sys
+    pub clear_env: bool,
+    pub env: Vec<(String, String)>,
+    pub cwd: Option<PathBuf>,
+    pub credentials: Option<Credentials>,
+    pub umask: Option<Mode>,
+    pub stdin: Option<File>,
+    pub stdout: Option<File>,
+    pub stderr: Option<File>,
+    /// File descriptors passed with `FD`, and the number to install them as
+    pub fds: Vec<(OwnedFd, RawFd)>,
+    /// Targets of the pipes requested with `PIPE`
+    pub pipes: Vec<RawFd>,
+    /// Hand back a pidfd of the new process
+    pub pidfd: bool,
+    /// Unit the new process belongs to
+    pub unit: Option<String>,
+    pub limits: Vec<Limit>,
+    pub isolation: Isolation,
+    pub grouping: Option<Grouping>,
+}
+
+pub fn nix_to_io(e: ::nix::Error) -> io::Error {
+    io::Error::from_raw_os_error(::errno_of(e))
+}
+
+impl ExecContext {
+    /// Opens `path` as the given standard stream of the next process.
+    ///
+    /// stdin is opened for reading, stdout and stderr are opened for
+    /// appending and created if needed.
+    pub fn set_stdio(&mut self, target: StdioTarget, path: &Path)
+            -> io::Result<()> {
+        match target {
+            StdioTarget::Stdin => {
+                self.stdin = Some(File::open(path)?);
+            },
+            StdioTarget::Stdout | StdioTarget::Stderr => {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .mode(STDIO_FILE_MODE)
+                    .open(path)?;
+
+                if target == StdioTarget::Stdout {
+                    self.stdout = Some(file);
+                } else {
+                    self.stderr = Some(file);
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Number of file descriptors handed back by the next START
+    pub fn reply_fd_count(&self) -> usize {
+        self.pipes.len() + self.pidfd as usize
+    }
+
+    /// Builds the command that starts `path` with `args` in this context.
+    ///
+    /// Also returns the read ends of the pipes requested with `PIPE`, in the
+    /// order they were requested.
+    pub fn into_command(self, path: &Path, args: &[String])
+            -> io::Result<(Command, Vec<OwnedFd>)> {
+        let mut cmd = Command::new(path);
+        cmd.args(args);
+
+        if self.clear_env {
+            cmd.env_clear();
+        }
+        /* Even after CLEARENV, a unit can tell which system it runs on */
+        cmd.envs(identity::environment());
+        cmd.envs(self.env);
+
+        if let Some(cwd) = self.cwd {
+            cmd.current_dir(cwd);
+        }
+
+        if let Some(stdin) = self.stdin {
+            cmd.stdin(Stdio::from(stdin));
+        }
+        /* Output that is not redirected is captured into the logs */
+        cmd.stdout(self.stdout.map_or_else(Stdio::piped, Stdio::from));
+        cmd.stderr(self.stderr.map_or_else(Stdio::piped, Stdio::from));
+
+        let mut fds = self.fds;
+        let mut pipe_ends = Vec::new();
+        for target in self.pipes {
+            let (r, w) = pipe2(OFlag::O_CLOEXEC).map_err(nix_to_io)?;
+            let (r, w) = unsafe {
+                (OwnedFd::from_raw_fd(r), OwnedFd::from_raw_fd(w))
+            };
+
+            pipe_ends.push(r);
+            fds.push((w, target));
+        }
+
+        /*
+         * Move the descriptors above all targets first, so that installing one
+         * of them can not clobber another that is still to be installed. The
+         * copies are owned by the command and closed along with it.
+         */
+        let floor = fds.iter()
+            .map(|&(_, target)| target + 1)
+            .max()
+            .unwrap_or(0);
+        let fds = fds.into_iter()
+            .map(|(fd, target)| {
+                let high = fcntl(fd.as_raw_fd(),
+                                 FcntlArg::F_DUPFD_CLOEXEC(floor))
+                    .map_err(nix_to_io)?;
+                Ok((unsafe { OwnedFd::from_raw_fd(high) }, target))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let cgroup = match self.unit {
+            Some(ref unit) => ::cgroup::procs_file(unit)?,
+            None => None,
+        };
+        let mask = self.umask;
+        let limits = self.limits;
+        let credentials = self.credentials;
+        let grouping = self.grouping;
+
+        let isolation = if self.isolation.is_empty() {
+            None
+        } else {
+            let (uid, gid) = credentials.as_ref()
+                .map_or((geteuid(), getegid()), |c| (c.uid, c.gid));
+            Some(self.isolation.prepare(uid, gid))
+        };
+        let user_namespace = isolation.as_ref()
+            .is_some_and(|i| i.has_user_namespace());
+
+        /*
+         * The credentials are dropped by hand rather than through
+         * Command::uid() and Command::gid(), since those would not keep the
+         * supplementary groups. This runs in the child between fork(2) and
+         * execve(2), so it must not allocate.
+         */
+        unsafe {
+            cmd.pre_exec(move || {
+                ::signals::unblock_all().map_err(nix_to_io)?;
+
+                /* Out of reach of signals sent to the group of sys */
+                match grouping {
+                    Some(Grouping::Session) => {
+                        setsid().map_err(nix_to_io)?;
+                    },
+                    Some(Grouping::ProcessGroup(pgid)) => {
+                        setpgid(Pid::from_raw(0), pgid).map_err(nix_to_io)?;
+                    },
+                    None => (),
+                }
+
+                /* Join the cgroup while we still have the rights to */
+                if let Some(ref procs) = cgroup {
+                    write(procs.as_raw_fd(), b"0").map_err(nix_to_io)?;
+                }
+
+                if let Some(mask) = mask {
+                    umask(mask);
+                }
+
+                /* Raising hard limits takes privileges we are about to drop */
+                for limit in &limits {
+                    let rlim = libc::rlimit {
+                        rlim_cur: limit.soft,
+                        rlim_max: limit.hard,
+                    };
+                    if libc::setrlimit(limit.resource, &rlim) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+
+                /* dup2(2) leaves the new descriptor without close-on-exec */
+                for &(ref fd, target) in &fds {
+                    dup2(fd.as_raw_fd(), target).map_err(nix_to_io)?;
+                }
+
+                /*
+                 * Setting up namespaces takes privileges, unless a user
+                 * namespace grants them, in which the credentials can no
+                 * longer be changed though.
+                 */
+                if !user_namespace {
+                    if let Some(ref isolation) = isolation {
+                        isolation.enter()?;
+                    }
+                }
+
+                if let Some(ref creds) = credentials {
+                    setgroups(&creds.groups).map_err(nix_to_io)?;
+                    setgid(creds.gid).map_err(nix_to_io)?;
+                    setuid(creds.uid).map_err(nix_to_io)?;
+                }
+
+                if user_namespace {
+                    if let Some(ref isolation) = isolation {
+                        isolation.enter()?;
+                    }
+                }
+
+                Ok(())
+            });
+        }
+
+        Ok((cmd, pipe_ends))
+    }
+}
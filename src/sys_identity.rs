@@ -0,0 +1,180 @@
+/* This file is part of the Aeterno init system. */
+
+/* Goal:
+ *  - Set the hostname at boot, from the kernel command line or /etc/hostname
+ *  - Know the machine-id, generating and persisting it on first boot
+ *  - Report both in HELO and to the processes sys starts
+ *
+ * The hostname is only set, and a new machine-id only persisted, when sys is
+ * PID 1. Otherwise sys reports what is there, and a machine-id that lasts as
+ * long as sys does if there is none.
+ */
+
+use std::cell::RefCell;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+
+use nix::unistd::{gethostname, getpid, sethostname, Pid};
+
+use uuid::Uuid;
+
+const KERNEL_CMDLINE_PATH: &str = "/proc/cmdline";
+const HOSTNAME_PATH: &str = "/etc/hostname";
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+/// Kernel command line option overriding /etc/hostname
+const HOSTNAME_OPTION: &str = "aeterno.hostname";
+const HOSTNAME_MAX: usize = 64;
+
+/// Environment variables given to every process started by sys
+const HOSTNAME_ENV: &str = "AETERNO_HOSTNAME";
+const MACHINE_ID_ENV: &str = "AETERNO_MACHINE_ID";
+
+lazy_static! {
+    static ref current_machine_id: Mutex<RefCell<String>>
+        = Mutex::new(RefCell::new(String::new()));
+}
+
+/// Finds the hostname given on a kernel command line, if any
+fn cmdline_hostname(cmdline: &str) -> Option<String> {
+    /* The last one wins, as with the options of the kernel */
+    cmdline.split_whitespace()
+        .rev()
+        .filter_map(|arg| arg.split_once('='))
+        .find(|&(key, value)| key == HOSTNAME_OPTION && !value.is_empty())
+        .map(|(_, value)| value.to_string())
+}
+
+/// Finds the hostname in the contents of /etc/hostname, skipping comments
+fn file_hostname(contents: &str) -> Option<String> {
+    contents.lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+}
+
+/// The hostname to set at boot
+fn configured_hostname() -> Option<String> {
+    fs::read_to_string(KERNEL_CMDLINE_PATH).ok()
+        .and_then(|cmdline| cmdline_hostname(&cmdline))
+        .or_else(|| {
+            fs::read_to_string(HOSTNAME_PATH).ok()
+                .and_then(|contents| file_hostname(&contents))
+        })
+}
+
+/// Whether `id` looks like a machine-id: 32 lowercase hexadecimal digits
+fn is_valid_machine_id(id: &str) -> bool {
+    id.len() == 32
+        && id.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+fn persist_machine_id(id: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o444)
+        .open(MACHINE_ID_PATH)?;
+
+    file.write_all(format!("{}\n", id).as_bytes())?;
+    file.sync_all()
+}
+
+/// Reads the machine-id, or makes one up if there is no valid one
+fn load_machine_id(persist: bool) -> String {
+    if let Ok(contents) = fs::read_to_string(MACHINE_ID_PATH) {
+        let id = contents.trim();
+        if is_valid_machine_id(id) {
+            return id.to_string();
+        }
+    }
+
+    let id = Uuid::new_v4().to_simple().to_string();
+    if persist {
+        match persist_machine_id(&id) {
+            Ok(()) => info!("Generated machine-id {}", id),
+            Err(e) => warn!("cannot persist machine-id {} to {}: {:?}",
+                            id, MACHINE_ID_PATH, e),
+        }
+    } else {
+        info!("No machine-id in {}, using {} until sys exits",
+              MACHINE_ID_PATH, id);
+    }
+
+    id
+}
+
+/// Sets the hostname and loads the machine-id.
+///
+/// Must be called before any connection is accepted.
+pub fn init() {
+    let pid1 = getpid() == Pid::from_raw(1);
+
+    if pid1 {
+        if let Some(name) = configured_hostname() {
+            match sethostname(&name) {
+                Ok(()) => info!("Hostname set to {:?}", name),
+                Err(e) => warn!("cannot set hostname {:?}: {:?}", name, e),
+            }
+        }
+    }
+
+    let id = load_machine_id(pid1);
+    current_machine_id.lock().unwrap().replace(id);
+}
+
+/// The current hostname, which may have been changed since boot
+pub fn hostname() -> String {
+    let mut buf = [0u8; HOSTNAME_MAX + 1];
+
+    match gethostname(&mut buf) {
+        Ok(name) => name.to_string_lossy().into_owned(),
+        Err(_) => "localhost".to_string(),
+    }
+}
+
+pub fn machine_id() -> String {
+    current_machine_id.lock().unwrap().borrow().clone()
+}
+
+/// The variables describing the system, for the processes sys starts
+pub fn environment() -> Vec<(&'static str, String)> {
+    vec![
+        (HOSTNAME_ENV, hostname()),
+        (MACHINE_ID_ENV, machine_id()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_last_cmdline_hostname() {
+        assert_eq!(cmdline_hostname("ro aeterno.hostname=first quiet \
+                                     aeterno.hostname=second\n"),
+                   Some("second".to_string()));
+        assert_eq!(cmdline_hostname("aeterno.hostname=kept \
+                                     aeterno.hostname="),
+                   Some("kept".to_string()));
+        assert_eq!(cmdline_hostname("hostname=other aeterno.hostname"), None);
+    }
+
+    #[test]
+    fn finds_the_file_hostname() {
+        assert_eq!(file_hostname("# set at install\n\n  box  \nother\n"),
+                   Some("box".to_string()));
+        assert_eq!(file_hostname("# only a comment\n \n"), None);
+        assert_eq!(file_hostname(""), None);
+    }
+
+    #[test]
+    fn checks_machine_ids() {
+        assert!(is_valid_machine_id("3d1219c7c4c5404aaa1f6d2a48adfda4"));
+        assert!(!is_valid_machine_id("3D1219C7C4C5404AAA1F6D2A48ADFDA4"));
+        assert!(!is_valid_machine_id("3d1219c7c4c5404aaa1f6d2a48adfda"));
+        assert!(!is_valid_machine_id("3d1219c7-c4c5-404a-aa1f-6d2a48adfda4"));
+    }
+}
@@ -0,0 +1,324 @@
+/* This file is part of the Aeterno init system. */
+
+/* Goal:
+ *  - Run a started process in namespaces of its own (UNSHARE)
+ *  - Give it a private /tmp (PRIVATETMP) and read-only views of paths
+ *    (READONLY) in its own mount namespace
+ *
+ * Everything that allocates is prepared by sys before the process is forked,
+ * the child only issues system calls. A user namespace maps root inside of it
+ * to the user the process runs as outside, which lets an unprivileged sys set
+ * up all the other namespaces as well.
+ */
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use nix::fcntl::{open, OFlag};
+use nix::libc;
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::signal::{kill, sigaction, SaFlags, SigAction, SigHandler};
+use nix::sys::signal::{Signal, SigSet};
+use nix::sys::stat::Mode;
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{close, fork, ForkResult, getpid, Gid, Pid, Uid, write};
+
+use exec::nix_to_io;
+
+/// Signals passed on by the process left outside of a new PID namespace
+const FORWARDED_SIGNALS: [Signal; 6] = [
+    Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP, Signal::SIGQUIT,
+    Signal::SIGUSR1, Signal::SIGUSR2,
+];
+
+/// Descriptors closed by hand if close_range(2) is not available
+const CLOSE_FDS_FALLBACK_MAX: RawFd = 1024;
+
+#[derive(Debug)]
+pub struct Isolation {
+    namespaces: CloneFlags,
+    private_tmp: bool,
+    /// Paths to make read-only, with the flags they are mounted with
+    read_only: Vec<(PathBuf, MsFlags)>,
+}
+
+impl Default for Isolation {
+    fn default() -> Isolation {
+        Isolation {
+            namespaces: CloneFlags::empty(),
+            private_tmp: false,
+            read_only: Vec::new(),
+        }
+    }
+}
+
+/// Parses the name of a namespace as used by UNSHARE
+pub fn parse_namespace(name: &str) -> Option<CloneFlags> {
+    match name {
+        "MOUNT" => Some(CloneFlags::CLONE_NEWNS),
+        "PID" => Some(CloneFlags::CLONE_NEWPID),
+        "NET" => Some(CloneFlags::CLONE_NEWNET),
+        "UTS" => Some(CloneFlags::CLONE_NEWUTS),
+        "IPC" => Some(CloneFlags::CLONE_NEWIPC),
+        "USER" => Some(CloneFlags::CLONE_NEWUSER),
+        _ => None,
+    }
+}
+
+/// Mount flags that must be kept when remounting a bind mount of a mount with
+/// the filesystem flags `flags`, since a user namespace may not clear them
+fn locked_mount_flags(flags: FsFlags) -> MsFlags {
+    let map = [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ];
+
+    map.iter()
+        .filter(|&&(st, _)| flags.contains(st))
+        .fold(MsFlags::empty(), |acc, &(_, ms)| acc | ms)
+}
+
+impl Isolation {
+    pub fn is_empty(&self) -> bool {
+        self.namespaces.is_empty()
+            && !self.private_tmp
+            && self.read_only.is_empty()
+    }
+
+    pub fn unshare(&mut self, namespaces: CloneFlags) {
+        self.namespaces |= namespaces;
+    }
+
+    pub fn set_private_tmp(&mut self) {
+        self.private_tmp = true;
+        self.namespaces |= CloneFlags::CLONE_NEWNS;
+    }
+
+    /// Makes `path` read-only for the process
+    pub fn add_read_only(&mut self, path: PathBuf) -> nix::Result<()> {
+        let flags = locked_mount_flags(statvfs(&path)?.flags());
+
+        self.read_only.push((path, flags));
+        self.namespaces |= CloneFlags::CLONE_NEWNS;
+        Ok(())
+    }
+
+    /// Prepares the isolation of a process that will run as `uid` and `gid`
+    pub fn prepare(self, uid: Uid, gid: Gid) -> Prepared {
+        Prepared {
+            uid_map: format!("0 {} 1\n", uid),
+            gid_map: format!("0 {} 1\n", gid),
+            isolation: self,
+        }
+    }
+}
+
+/// An isolation ready to be entered by the child
+#[derive(Debug)]
+pub struct Prepared {
+    isolation: Isolation,
+    uid_map: String,
+    gid_map: String,
+}
+
+/// Writes `data` to the file at `path`
+fn write_file(path: &str, data: &[u8]) -> nix::Result<()> {
+    let fd = open(path, OFlag::O_WRONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+    let res = write(fd, data);
+    let _ = close(fd);
+
+    res.map(|_| ())
+}
+
+/* The child of the process left outside of a new PID namespace */
+static NAMESPACE_INIT: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(sig: libc::c_int) {
+    let pid = NAMESPACE_INIT.load(Ordering::SeqCst);
+    if pid > 0 {
+        unsafe { libc::kill(pid, sig) };
+    }
+}
+
+fn set_handler(sig: Signal, handler: SigHandler) {
+    let action = SigAction::new(handler, SaFlags::SA_RESTART, SigSet::empty());
+    let _ = unsafe { sigaction(sig, &action) };
+}
+
+/// Closes every descriptor above the standard streams
+fn close_extra_fds() {
+    let res = unsafe {
+        libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0)
+    };
+
+    if res != 0 {
+        for fd in 3..CLOSE_FDS_FALLBACK_MAX {
+            let _ = close(fd);
+        }
+    }
+}
+
+/// Waits for `child` and then exits the same way it did
+fn mirror_child(child: Pid) -> ! {
+    NAMESPACE_INIT.store(i32::from(child), Ordering::SeqCst);
+    for sig in &FORWARDED_SIGNALS {
+        set_handler(*sig, SigHandler::Handler(forward_signal));
+    }
+
+    /* Nothing of sys is needed here, and it must not keep anything open */
+    close_extra_fds();
+
+    loop {
+        match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => unsafe { libc::_exit(code) },
+            Ok(WaitStatus::Signaled(_, sig, _)) => {
+                set_handler(sig, SigHandler::SigDfl);
+                let _ = kill(getpid(), sig);
+                unsafe { libc::_exit(128 + sig as i32) };
+            },
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Ok(_) => continue,
+            Err(_) => unsafe { libc::_exit(1) },
+        }
+    }
+}
+
+/// Bind mounts `path` onto itself and makes it read-only
+fn mount_read_only(path: &Path, flags: MsFlags) -> nix::Result<()> {
+    mount(Some(path), path, None::<&str>,
+          MsFlags::MS_BIND | MsFlags::MS_REC, None::<&str>)?;
+    mount(None::<&str>, path, None::<&str>,
+          MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | flags,
+          None::<&str>)
+}
+
+impl Prepared {
+    /// Whether the credentials must be dropped before entering, since they
+    /// can not be changed from within the user namespace
+    pub fn has_user_namespace(&self) -> bool {
+        self.isolation.namespaces.contains(CloneFlags::CLONE_NEWUSER)
+    }
+
+    /// Enters the isolation. This runs in the child between fork(2) and
+    /// execve(2), so it must not allocate.
+    ///
+    /// With a new PID namespace, the calling process stays outside of it and
+    /// never returns, it passes signals on to its only child and exits along
+    /// with it. The child returns and goes on to become the init process of
+    /// the namespace.
+    pub fn enter(&self) -> io::Result<()> {
+        let namespaces = self.isolation.namespaces;
+
+        if namespaces.contains(CloneFlags::CLONE_NEWUSER) {
+            unshare(CloneFlags::CLONE_NEWUSER).map_err(nix_to_io)?;
+
+            /* Unprivileged processes may only map groups without setgroups */
+            write_file("/proc/self/setgroups", b"deny").map_err(nix_to_io)?;
+            write_file("/proc/self/uid_map", self.uid_map.as_bytes())
+                .map_err(nix_to_io)?;
+            write_file("/proc/self/gid_map", self.gid_map.as_bytes())
+                .map_err(nix_to_io)?;
+        }
+
+        let others = namespaces - CloneFlags::CLONE_NEWUSER;
+        if !others.is_empty() {
+            unshare(others).map_err(nix_to_io)?;
+        }
+
+        let new_mounts = namespaces.contains(CloneFlags::CLONE_NEWNS);
+        if new_mounts {
+            /* Keep our mounts from propagating back to the rest */
+            mount(None::<&str>, "/", None::<&str>,
+                  MsFlags::MS_REC | MsFlags::MS_PRIVATE, None::<&str>)
+                .map_err(nix_to_io)?;
+
+            if self.isolation.private_tmp {
+                mount(Some("tmpfs"), "/tmp", Some("tmpfs"),
+                      MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                      Some("mode=1777"))
+                    .map_err(nix_to_io)?;
+            }
+
+            for &(ref path, flags) in &self.isolation.read_only {
+                mount_read_only(path, flags).map_err(nix_to_io)?;
+            }
+        }
+
+        if namespaces.contains(CloneFlags::CLONE_NEWPID) {
+            match fork().map_err(nix_to_io)? {
+                ForkResult::Parent { child } => mirror_child(child),
+                ForkResult::Child => {
+                    /* Go down along with the process sys knows about */
+                    unsafe {
+                        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+                    }
+
+                    if new_mounts {
+                        mount(Some("proc"), "/proc", Some("proc"),
+                              MsFlags::MS_NOSUID | MsFlags::MS_NODEV
+                              | MsFlags::MS_NOEXEC,
+                              None::<&str>)
+                            .map_err(nix_to_io)?;
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    use exec::ExecContext;
+
+    /// Whether this kernel lets us create a user namespace
+    fn user_namespaces_available() -> bool {
+        let mut probe = Command::new("true");
+        unsafe {
+            probe.pre_exec(|| {
+                unshare(CloneFlags::CLONE_NEWUSER).map_err(nix_to_io)
+            });
+        }
+
+        probe.status().is_ok_and(|s| s.success())
+    }
+
+    #[test]
+    fn runs_as_root_with_an_empty_tmp() {
+        if !user_namespaces_available() {
+            eprintln!("user namespaces are not available, skipping");
+            return;
+        }
+
+        /* Something the process must not see */
+        let marker = format!("/tmp/aeterno-isolation-{}", getpid());
+        std::fs::write(&marker, b"").unwrap();
+
+        let mut ctx = ExecContext::default();
+        ctx.isolation.unshare(CloneFlags::CLONE_NEWUSER
+                              | CloneFlags::CLONE_NEWNET);
+        ctx.isolation.set_private_tmp();
+
+        let args = ["-c".to_string(), "id -u && ls -A /tmp".to_string()];
+        let (mut cmd, _) = ctx.into_command(Path::new("sh"), &args).unwrap();
+        let output = cmd.output().unwrap();
+        std::fs::remove_file(&marker).unwrap();
+
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "0\n");
+    }
+}
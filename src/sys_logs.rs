@@ -0,0 +1,378 @@
+/* This file is part of the Aeterno init system. */
+
+/* Goal:
+ *  - Capture the standard output and error of the processes started by START
+ *  - Keep the latest lines of each process, tagged with its pid and unit
+ *  - Answer LOGS queries and stream new lines to connections that FOLLOW
+ */
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::sys::socket::{MsgFlags, send};
+use nix::unistd::Pid;
+
+use quoting;
+
+/// Lines kept per process, older lines are dropped
+const LOG_LINES_MAX: usize = 1000;
+/// Longer lines are split
+const LOG_LINE_MAX: usize = 1024;
+/// How long to wait for a reply on a follower's connection to go out
+const FOLLOWER_BUSY_MAX: Duration = Duration::from_secs(1);
+/// Logs kept around for processes whose output has ended
+const FINISHED_LOGS_MAX: usize = 64;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug)]
+struct ProcessLog {
+    pid: Pid,
+    unit: Option<String>,
+    lines: VecDeque<(Stream, String)>,
+    /// Captured streams that have not reached end-of-file yet, with the
+    /// descriptors they are read from
+    open_streams: Vec<(Stream, RawFd)>,
+    /// Connections that receive new lines as they arrive
+    followers: Vec<RawFd>,
+}
+
+impl ProcessLog {
+    /// Formats a line as a `LOG` line of the protocol
+    fn describe(&self, stream: Stream, text: &str) -> String {
+        let stream = match stream {
+            Stream::Stdout => "OUT",
+            Stream::Stderr => "ERR",
+        };
+
+        format!("LOG {} {} {} {}\n", self.pid,
+                self.unit.as_ref().map_or_else(|| "-".to_string(),
+                                               |u| quoting::quote(u)),
+                stream, quoting::quote(text))
+    }
+}
+
+lazy_static! {
+    static ref process_logs: Mutex<RefCell<Vec<ProcessLog>>>
+        = Mutex::new(RefCell::new(Vec::new()));
+}
+
+/// Formats the line marking the end of the output of `pid`
+pub fn end_of_log(pid: Pid) -> String {
+    format!("LOGEND {}\n", pid)
+}
+
+/// Starts capturing the given output streams of the process `pid`
+pub fn capture<O, E>(pid: Pid, unit: Option<String>,
+                     stdout: Option<O>, stderr: Option<E>)
+    where O: Read + AsRawFd + Send + 'static,
+          E: Read + AsRawFd + Send + 'static {
+    {
+        let logs_cell = process_logs.lock().unwrap();
+        let mut logs = logs_cell.borrow_mut();
+
+        /* The pid may be reused by now */
+        logs.retain(|l| l.pid != pid);
+
+        /* Make room by forgetting the oldest finished processes */
+        let mut finished = logs.iter()
+            .filter(|l| l.open_streams.is_empty())
+            .count();
+        logs.retain(|l| {
+            if l.open_streams.is_empty() && finished > FINISHED_LOGS_MAX {
+                finished -= 1;
+                false
+            } else {
+                true
+            }
+        });
+
+        let open_streams = stdout.as_ref()
+            .map(|o| (Stream::Stdout, o.as_raw_fd()))
+            .into_iter()
+            .chain(stderr.as_ref().map(|e| (Stream::Stderr, e.as_raw_fd())))
+            .collect();
+        logs.push(ProcessLog {
+            pid,
+            unit,
+            lines: VecDeque::new(),
+            open_streams,
+            followers: Vec::new(),
+        });
+    }
+
+    if let Some(stdout) = stdout {
+        thread::spawn(move || read_stream(pid, Stream::Stdout, stdout));
+    }
+    if let Some(stderr) = stderr {
+        thread::spawn(move || read_stream(pid, Stream::Stderr, stderr));
+    }
+}
+
+/// Collects the lines written to `stream` until it is closed
+fn read_stream<R: Read>(pid: Pid, stream: Stream, reader: R) {
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::new();
+
+    loop {
+        buf.clear();
+        let res = reader.by_ref()
+            .take(LOG_LINE_MAX as u64)
+            .read_until(b'\n', &mut buf);
+
+        match res {
+            Ok(0) => break,
+            Ok(_) => {
+                if buf.last() == Some(&b'\n') {
+                    buf.pop();
+                }
+                let text = String::from_utf8_lossy(&buf).into_owned();
+                append(pid, stream, text);
+            },
+            Err(e) => {
+                warn!("failed to read {:?} of {:?}: {:?}", stream, pid, e);
+                break;
+            },
+        }
+    }
+
+    close_stream(pid, stream);
+}
+
+/// Sends `line` to a follower without waiting for it to read.
+///
+/// Returns whether the whole line went out, a follower that is not keeping up
+/// would otherwise hold up the output of the process and every other log.
+fn send_to_follower(fd: RawFd, line: &str) -> bool {
+    match send(fd, line.as_bytes(), MsgFlags::MSG_DONTWAIT) {
+        Ok(sent) if sent == line.len() => true,
+        Ok(_) | Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => {
+            warn!("FD {} is not keeping up with the logs, no longer following",
+                  fd);
+            false
+        },
+        Err(_) => false,
+    }
+}
+
+/// Sends `line` to those of `fds` that still follow `pid`, and stops the
+/// follow of any that can not take it.
+///
+/// With `end`, the follows end after the line. A line is never written into
+/// the middle of a reply on the same connection.
+fn send_to_followers(pid: Pid, fds: Vec<RawFd>, line: &str, end: bool) {
+    for fd in fds {
+        let writer = ::conn_writer(fd);
+        let started = Instant::now();
+        let writing = loop {
+            match writer.try_lock() {
+                Ok(writing) => break Some(writing),
+                Err(_) if started.elapsed() >= FOLLOWER_BUSY_MAX => break None,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        /* The connection may have been closed and its FD reused meanwhile */
+        let following = {
+            let logs_cell = process_logs.lock().unwrap();
+            let logs = logs_cell.borrow();
+
+            logs.iter()
+                .find(|l| l.pid == pid)
+                .is_some_and(|l| l.followers.contains(&fd))
+        };
+        if !following {
+            continue;
+        }
+
+        let sent = match writing {
+            Some(_) => {
+                let sent = send_to_follower(fd, line);
+                ::send_held_events(fd);
+                sent
+            },
+            None => {
+                warn!("FD {} is busy, no longer following {}", fd, pid);
+                false
+            },
+        };
+        if end || !sent {
+            unfollow(pid, fd);
+        }
+    }
+}
+
+/// Stores a line of output and sends it to the followers of the process
+fn append(pid: Pid, stream: Stream, text: String) {
+    let (followers, line) = {
+        let logs_cell = process_logs.lock().unwrap();
+        let mut logs = logs_cell.borrow_mut();
+
+        let log = match logs.iter_mut().find(|l| l.pid == pid) {
+            Some(log) => log,
+            None => return,
+        };
+
+        let line = log.describe(stream, &text);
+        if log.lines.len() >= LOG_LINES_MAX {
+            log.lines.pop_front();
+        }
+        log.lines.push_back((stream, text));
+
+        (log.followers.clone(), line)
+    };
+
+    send_to_followers(pid, followers, &line, false);
+}
+
+/// Marks one stream of `pid` as closed, ending the follows once all are
+fn close_stream(pid: Pid, stream: Stream) {
+    let followers = {
+        let logs_cell = process_logs.lock().unwrap();
+        let mut logs = logs_cell.borrow_mut();
+
+        match logs.iter_mut().find(|l| l.pid == pid) {
+            Some(log) => {
+                log.open_streams.retain(|&(s, _)| s != stream);
+                if log.open_streams.is_empty() {
+                    log.followers.clone()
+                } else {
+                    return;
+                }
+            },
+            None => return,
+        }
+    };
+
+    send_to_followers(pid, followers, &end_of_log(pid), true);
+}
+
+/// Returns the last `count` lines of `pid` as `LOG` lines, or all of them.
+///
+/// `None` if the output of `pid` is not known.
+pub fn tail(pid: Pid, count: Option<usize>) -> Option<Vec<String>> {
+    let logs_cell = process_logs.lock().unwrap();
+    let logs = logs_cell.borrow();
+
+    let log = logs.iter().find(|l| l.pid == pid)?;
+    let skip = count.map_or(0, |c| log.lines.len().saturating_sub(c));
+
+    Some(log.lines.iter()
+         .skip(skip)
+         .map(|&(stream, ref text)| log.describe(stream, text))
+         .collect())
+}
+
+/// Sends the new output of `pid` to `conn_fd` from now on.
+///
+/// Returns whether the output of `pid` is still being captured, `None` if it
+/// is not known at all. `LOGEND` is sent to `conn_fd` once the output ends.
+pub fn follow(pid: Pid, conn_fd: RawFd) -> Option<bool> {
+    let logs_cell = process_logs.lock().unwrap();
+    let mut logs = logs_cell.borrow_mut();
+
+    let log = logs.iter_mut().find(|l| l.pid == pid)?;
+    if log.open_streams.is_empty() {
+        return Some(false);
+    }
+
+    if !log.followers.contains(&conn_fd) {
+        log.followers.push(conn_fd);
+    }
+
+    Some(true)
+}
+
+/// Stops sending the output of `pid` to `conn_fd`
+pub fn unfollow(pid: Pid, conn_fd: RawFd) {
+    let logs_cell = process_logs.lock().unwrap();
+    let mut logs = logs_cell.borrow_mut();
+
+    if let Some(log) = logs.iter_mut().find(|l| l.pid == pid) {
+        log.followers.retain(|fd| *fd != conn_fd);
+    }
+}
+
+/// Stops sending any output to `conn_fd`, as it is about to be closed
+pub fn unfollow_all(conn_fd: RawFd) {
+    let logs_cell = process_logs.lock().unwrap();
+    let mut logs = logs_cell.borrow_mut();
+
+    for log in logs.iter_mut() {
+        log.followers.retain(|fd| *fd != conn_fd);
+    }
+}
+
+/// Lists every line kept for `LOGS`, with the pid and unit of its process
+pub fn history() -> Vec<(Pid, Option<String>, Stream, String)> {
+    let logs_cell = process_logs.lock().unwrap();
+    let logs = logs_cell.borrow();
+
+    logs.iter()
+        .flat_map(|l| {
+            l.lines.iter()
+                .map(move |&(s, ref text)| {
+                    (l.pid, l.unit.clone(), s, text.clone())
+                })
+        })
+        .collect()
+}
+
+/// Takes over the lines kept by a previous image of sys, as listed by
+/// `history`, in front of what has been captured since
+pub fn adopt(history: Vec<(Pid, Option<String>, Stream, String)>) {
+    let mut adopted: Vec<ProcessLog> = Vec::new();
+    for (pid, unit, stream, text) in history {
+        match adopted.last_mut() {
+            Some(log) if log.pid == pid => log.lines.push_back((stream, text)),
+            _ => adopted.push(ProcessLog {
+                pid,
+                unit,
+                lines: VecDeque::from(vec![(stream, text)]),
+                open_streams: Vec::new(),
+                followers: Vec::new(),
+            }),
+        }
+    }
+
+    let logs_cell = process_logs.lock().unwrap();
+    let mut logs = logs_cell.borrow_mut();
+
+    for log in logs.drain(..) {
+        match adopted.iter_mut().find(|l| l.pid == log.pid) {
+            Some(earlier) => {
+                earlier.lines.extend(log.lines);
+                while earlier.lines.len() > LOG_LINES_MAX {
+                    earlier.lines.pop_front();
+                }
+                earlier.open_streams = log.open_streams;
+                earlier.followers = log.followers;
+            },
+            None => adopted.push(log),
+        }
+    }
+
+    *logs = adopted;
+}
+
+/// Lists the streams still being captured, with the descriptors they are
+/// read from
+pub fn open_streams() -> Vec<(Pid, Stream, RawFd)> {
+    let logs_cell = process_logs.lock().unwrap();
+    let logs = logs_cell.borrow();
+
+    logs.iter()
+        .flat_map(|l| {
+            l.open_streams.iter().map(move |&(s, fd)| (l.pid, s, fd))
+        })
+        .collect()
+}
@@ -0,0 +1,274 @@
+/* This file is part of the Aeterno init system. */
+
+/* Goal:
+ *  - Keep track of the processes started through START
+ *  - Remember how the last few of them exited, once they are reaped
+ *  - Answer LIST and STATUS queries about them
+ */
+
+use std::cell::RefCell;
+use std::io;
+use std::process::{Child, Command};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
+
+use quoting;
+
+/// Number of exited processes that are remembered
+const EXITED_MAX: usize = 64;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ProcessState {
+    Running,
+    Stopped,
+    /// Exited with the given exit code
+    Exited(i32),
+    /// Terminated by the given signal
+    Signaled(i32),
+}
+
+impl ProcessState {
+    /// Whether the process has not been reaped yet
+    pub fn is_alive(self) -> bool {
+        matches!(self, ProcessState::Running | ProcessState::Stopped)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Process {
+    pub pid: Pid,
+    pub argv: Vec<String>,
+    pub state: ProcessState,
+    /// Unit the process was started for
+    pub unit: Option<String>,
+    pub started: SystemTime,
+}
+
+impl Process {
+    /// Formats the process as a `PROC` line of the protocol
+    pub fn describe(&self) -> String {
+        let (state, status) = match self.state {
+            ProcessState::Running => ("RUNNING", "-".to_string()),
+            ProcessState::Stopped => ("STOPPED", "-".to_string()),
+            ProcessState::Exited(code) => ("EXITED", code.to_string()),
+            ProcessState::Signaled(sig) => ("SIGNALED", sig.to_string()),
+        };
+        let started = self.started.duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let unit = self.unit.as_ref().map_or("-", |u| u.as_str());
+
+        format!("PROC {} {} {} {} {} {}\n", self.pid, state, status, started,
+                quoting::quote(unit), quoting::join(&self.argv))
+    }
+
+    /// Parses a `PROC` line, as formatted by `describe` but without the
+    /// newline
+    pub fn parse(line: &str) -> Option<Process> {
+        let args = quoting::split(line).ok()?;
+        if args.len() < 6 || args[0] != "PROC" {
+            return None;
+        }
+
+        let status = || args[3].parse::<i32>().ok();
+        let state = match args[2].as_str() {
+            "RUNNING" => ProcessState::Running,
+            "STOPPED" => ProcessState::Stopped,
+            "EXITED" => ProcessState::Exited(status()?),
+            "SIGNALED" => ProcessState::Signaled(status()?),
+            _ => return None,
+        };
+        let started = UNIX_EPOCH
+            + Duration::from_secs(args[4].parse::<u64>().ok()?);
+
+        Some(Process {
+            pid: Pid::from_raw(args[1].parse::<i32>().ok()?),
+            argv: args[6..].to_vec(),
+            state,
+            unit: Some(args[5].clone()).filter(|u| u != "-"),
+            started,
+        })
+    }
+}
+
+lazy_static! {
+    static ref process_table: Mutex<RefCell<Vec<Process>>>
+        = Mutex::new(RefCell::new(Vec::new()));
+}
+
+/// Spawns `cmd` and starts tracking the new process.
+///
+/// The table is locked while spawning, so that the wait event of a process
+/// that exits right away can not be processed before it is tracked. The child
+/// is never waited for through the returned handle, since all children are
+/// reaped by the main loop.
+pub fn spawn(cmd: &mut Command, argv: Vec<String>, unit: Option<String>)
+        -> io::Result<Child> {
+    let table_cell = process_table.lock().unwrap();
+    let mut table = table_cell.borrow_mut();
+
+    let child = cmd.spawn()?;
+    let pid = Pid::from_raw(child.id() as i32);
+
+    /* The pid may have belonged to a process that exited before */
+    table.retain(|p| p.pid != pid);
+    table.push(Process {
+        pid,
+        argv,
+        state: ProcessState::Running,
+        unit,
+        started: SystemTime::now(),
+    });
+
+    Ok(child)
+}
+
+/// Starts tracking processes that were started by a previous image of sys
+pub fn adopt(processes: Vec<Process>) {
+    let table_cell = process_table.lock().unwrap();
+    let mut table = table_cell.borrow_mut();
+
+    table.extend(processes);
+}
+
+/// Records that the process `pid` is gone, and forgets the process that
+/// exited first if too many are remembered
+fn record_exit(table: &mut Vec<Process>, pid: Pid, state: ProcessState)
+        -> Option<Process> {
+    let idx = table.iter().position(|p| p.pid == pid && p.state.is_alive())?;
+
+    /* Exited processes are kept at the end, in the order they exited */
+    let mut process = table.remove(idx);
+    process.state = state;
+    table.push(process.clone());
+
+    if table.iter().filter(|p| !p.state.is_alive()).count() > EXITED_MAX {
+        if let Some(oldest) = table.iter().position(|p| !p.state.is_alive()) {
+            table.remove(oldest);
+        }
+    }
+
+    Some(process)
+}
+
+/// Updates the table according to a wait event
+///
+/// Returns the process, if it exited.
+pub fn update(wait: &WaitStatus) -> Option<Process> {
+    let table_cell = process_table.lock().unwrap();
+    let mut table = table_cell.borrow_mut();
+
+    match *wait {
+        WaitStatus::Exited(pid, code) => {
+            return record_exit(&mut table, pid, ProcessState::Exited(code));
+        },
+        WaitStatus::Signaled(pid, sig, _) => {
+            return record_exit(&mut table, pid,
+                               ProcessState::Signaled(sig as i32));
+        },
+        WaitStatus::Stopped(pid, _) => {
+            if let Some(p) = table.iter_mut()
+                    .find(|p| p.pid == pid && p.state.is_alive()) {
+                p.state = ProcessState::Stopped;
+            }
+        },
+        WaitStatus::Continued(pid) => {
+            if let Some(p) = table.iter_mut()
+                    .find(|p| p.pid == pid && p.state.is_alive()) {
+                p.state = ProcessState::Running;
+            }
+        },
+        _ => (),
+    }
+
+    None
+}
+
+/// Returns a snapshot of all tracked processes, including the ones that
+/// exited recently
+pub fn list() -> Vec<Process> {
+    let table_cell = process_table.lock().unwrap();
+    let table = table_cell.borrow();
+
+    table.clone()
+}
+
+/// Returns the tracked process with the given `pid`, if any
+pub fn status(pid: Pid) -> Option<Process> {
+    let table_cell = process_table.lock().unwrap();
+    let table = table_cell.borrow();
+
+    table.iter()
+        .find(|p| p.pid == pid)
+        .cloned()
+}
+
+/// A process that was started at a fixed time, for tests
+#[cfg(test)]
+pub fn test_process(pid: i32, argv: &[&str], state: ProcessState,
+                    unit: Option<&str>) -> Process {
+    Process {
+        pid: Pid::from_raw(pid),
+        argv: argv.iter().map(|a| a.to_string()).collect(),
+        state,
+        unit: unit.map(str::to_string),
+        started: UNIX_EPOCH + Duration::from_secs(1_500_000_000),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_processes() {
+        assert_eq!(test_process(1234, &["sleep", "5"], ProcessState::Running,
+                                None).describe(),
+                   "PROC 1234 RUNNING - 1500000000 - sleep 5\n");
+        assert_eq!(test_process(1234, &["true"], ProcessState::Exited(3),
+                                Some("web")).describe(),
+                   "PROC 1234 EXITED 3 1500000000 web true\n");
+        assert_eq!(test_process(1234, &["sh", "-c", "echo 'hi'"],
+                                ProcessState::Signaled(9), Some("a b"))
+                       .describe(),
+                   "PROC 1234 SIGNALED 9 1500000000 \"a b\" sh -c \
+                    \"echo 'hi'\"\n");
+    }
+
+    #[test]
+    fn parses_what_it_describes() {
+        let processes = [
+            test_process(1234, &["sleep", "5"], ProcessState::Running, None),
+            test_process(1234, &["cat"], ProcessState::Stopped, Some("x")),
+            test_process(1234, &["sh", "-c", "echo \"$HOME\"\n", ""],
+                         ProcessState::Exited(-1), Some("unit name")),
+            test_process(1234, &[], ProcessState::Signaled(15), None),
+        ];
+
+        for p in &processes {
+            let line = p.describe();
+            let line = line.trim_end_matches('\n');
+            assert_eq!(Process::parse(line).as_ref(), Some(p), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let invalid = [
+            "",
+            "PROC 1234 RUNNING - 0",
+            "LIST 1234 RUNNING - 0 - true",
+            "PROC pid RUNNING - 0 - true",
+            "PROC 1234 ZOMBIE - 0 - true",
+            "PROC 1234 EXITED - 0 - true",
+            "PROC 1234 RUNNING - yesterday - true",
+            "PROC 1234 RUNNING - 0 - \"true",
+        ];
+
+        for line in &invalid {
+            assert_eq!(Process::parse(line), None, "{:?}", line);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+/* This file is part of the Aeterno init system. */
+
+/* Goal:
+ *  - Replace the running image of sys with a new one (REEXEC), e.g. after an
+ *    upgrade, without losing track of anything
+ *
+ * The state is written, one item per line, to a memfd that the new image
+ * finds through the environment. Everything it refers to by descriptor is
+ * kept open across execve(2), the children stay our children anyway.
+ */
+
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::libc;
+use nix::unistd::{execve, Pid};
+
+use exec::nix_to_io;
+use logs::Stream;
+use proctable::Process;
+use quoting;
+
+/// Environment variable holding the descriptor of the state, after REEXEC
+const STATE_FD_ENV: &str = "AETERNO_SYS_STATE_FD";
+
+/// What sys hands over to its new image
+#[derive(Debug, Default, PartialEq)]
+pub struct State {
+    pub master: Option<RawFd>,
+    pub master_pid: Option<Pid>,
+    pub processes: Vec<Process>,
+    /// Output of the processes that is being captured
+    pub streams: Vec<(Pid, Stream, RawFd)>,
+    /// Output kept for `LOGS`, with the pid and unit of its process
+    pub logs: Vec<(Pid, Option<String>, Stream, String)>,
+    /// Events waiting for a master
+    pub events: Vec<String>,
+    pub dropped: u64,
+    /// Processes to send SIGKILL once their STOP timeout runs out, with the
+    /// time that happens at on CLOCK_MONOTONIC
+    pub stops: Vec<(Pid, Duration)>,
+}
+
+fn stream_name(stream: Stream) -> &'static str {
+    match stream {
+        Stream::Stdout => "OUT",
+        Stream::Stderr => "ERR",
+    }
+}
+
+fn parse_stream_name(name: &str) -> Option<Stream> {
+    match name {
+        "OUT" => Some(Stream::Stdout),
+        "ERR" => Some(Stream::Stderr),
+        _ => None,
+    }
+}
+
+/// The time on CLOCK_MONOTONIC, which unlike `Instant` can be handed over
+fn monotonic_now() -> Duration {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Converts `deadline` to a time on CLOCK_MONOTONIC
+pub fn monotonic_deadline(deadline: Instant) -> Duration {
+    monotonic_now() + deadline.saturating_duration_since(Instant::now())
+}
+
+/// Converts a time on CLOCK_MONOTONIC back to an `Instant`
+pub fn instant_deadline(deadline: Duration) -> Instant {
+    Instant::now() + deadline.saturating_sub(monotonic_now())
+}
+
+impl State {
+    fn serialize(&self) -> String {
+        let mut out = String::new();
+
+        if let Some(fd) = self.master {
+            out.push_str(&format!("MASTER {}\n", fd));
+        }
+        if let Some(pid) = self.master_pid {
+            out.push_str(&format!("MASTERPID {}\n", pid));
+        }
+        for p in &self.processes {
+            out.push_str(&p.describe());
+        }
+        for &(pid, stream, fd) in &self.streams {
+            out.push_str(&format!("STREAM {} {} {}\n",
+                                  pid, stream_name(stream), fd));
+        }
+        for &(pid, ref unit, stream, ref text) in &self.logs {
+            let unit = unit.as_deref().unwrap_or("-");
+            out.push_str(&format!("LOG {} {} {} {}\n",
+                                  pid, quoting::quote(unit),
+                                  stream_name(stream), quoting::quote(text)));
+        }
+        for event in &self.events {
+            out.push_str(&format!("EVENT {}\n", quoting::quote(event)));
+        }
+        out.push_str(&format!("DROPPED {}\n", self.dropped));
+        for &(pid, deadline) in &self.stops {
+            out.push_str(&format!("STOPWATCH {} {}\n",
+                                  pid, deadline.as_millis()));
+        }
+
+        out
+    }
+
+    /// Applies one line of the serialized state
+    fn parse_line(&mut self, line: &str) -> Option<()> {
+        let args = quoting::split(line).ok()?;
+        let num = |i: usize| args.get(i)?.parse::<i32>().ok();
+
+        match args.first()?.as_str() {
+            "MASTER" => self.master = Some(num(1)?),
+            "MASTERPID" => self.master_pid = Some(Pid::from_raw(num(1)?)),
+            "PROC" => self.processes.push(Process::parse(line)?),
+            "STREAM" => {
+                let stream = parse_stream_name(args.get(2)?)?;
+
+                self.streams.push((Pid::from_raw(num(1)?), stream, num(3)?));
+            },
+            "LOG" if args.len() == 5 => {
+                let unit = Some(args[2].clone()).filter(|u| u != "-");
+                let stream = parse_stream_name(&args[3])?;
+
+                self.logs.push((Pid::from_raw(num(1)?), unit, stream,
+                                args[4].clone()));
+            },
+            "EVENT" => self.events.push(args.get(1)?.clone()),
+            "DROPPED" => self.dropped = args.get(1)?.parse().ok()?,
+            "STOPWATCH" => {
+                let deadline = args.get(2)?.parse::<u64>().ok()?;
+                self.stops.push((Pid::from_raw(num(1)?),
+                                 Duration::from_millis(deadline)));
+            },
+            _ => return None,
+        }
+
+        Some(())
+    }
+
+    fn parse(s: &str) -> State {
+        let mut state = State::default();
+
+        for line in s.lines() {
+            if state.parse_line(line).is_none() {
+                warn!("ignoring invalid state line {:?}", line);
+            }
+        }
+
+        state
+    }
+
+    /// The descriptors that must survive execve(2)
+    fn fds(&self) -> Vec<RawFd> {
+        self.master.into_iter()
+            .chain(self.streams.iter().map(|&(_, _, fd)| fd))
+            .collect()
+    }
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> nix::Result<()> {
+    let flags = if cloexec { FdFlag::FD_CLOEXEC } else { FdFlag::empty() };
+
+    fcntl(fd, FcntlArg::F_SETFD(flags)).map(|_| ())
+}
+
+/// Writes `state` to a memfd that is kept open across execve(2)
+fn write_state(state: &State) -> io::Result<File> {
+    let name = CString::new("aeterno-sys-state").unwrap();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(state.serialize().as_bytes())?;
+    file.seek(SeekFrom::Start(0))?;
+
+    Ok(file)
+}
+
+fn to_cstring(s: OsString) -> io::Result<CString> {
+    CString::new(s.into_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Executes `path` with our arguments and environment, and the state at
+/// `state_fd`. Returns only on failure.
+fn exec_image(path: &Path, state_fd: RawFd) -> io::Result<()> {
+    let path = to_cstring(path.as_os_str().to_os_string())?;
+    let args = env::args_os()
+        .map(to_cstring)
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut vars = env::vars_os()
+        .filter(|(k, _)| k != STATE_FD_ENV)
+        .map(|(mut k, v)| {
+            k.push("=");
+            k.push(v);
+            to_cstring(k)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    vars.push(to_cstring(format!("{}={}", STATE_FD_ENV, state_fd).into())?);
+
+    info!("Re-executing aeterno-sys from {:?}", path);
+    let Err(e) = execve(&path, &args, &vars);
+    Err(nix_to_io(e))
+}
+
+/// Replaces sys with the image at `path`, handing `state` over to it.
+///
+/// The descriptors `inherited` are kept open as well. Returns only on
+/// failure, after putting things back as they were.
+pub fn exec(path: &Path, state: &State, inherited: &[RawFd]) -> io::Error {
+    let state_file = match write_state(state) {
+        Ok(file) => file,
+        Err(e) => return e,
+    };
+
+    let fds = state.fds().into_iter()
+        .chain(inherited.iter().cloned())
+        .collect::<Vec<_>>();
+    for fd in &fds {
+        if let Err(e) = set_cloexec(*fd, false) {
+            warn!("cannot keep FD {} across execve(2): {:?}", fd, e);
+        }
+    }
+
+    let e = exec_image(path, state_file.as_raw_fd()).unwrap_err();
+
+    for fd in &fds {
+        let _ = set_cloexec(*fd, true);
+    }
+
+    e
+}
+
+/// Picks up the state handed over by a previous image of sys, if any.
+///
+/// The descriptors of the state are close-on-exec again afterwards.
+pub fn take_state() -> Option<State> {
+    let fd = env::var(STATE_FD_ENV).ok()?.parse::<RawFd>().ok()?;
+    env::remove_var(STATE_FD_ENV);
+
+    let mut data = String::new();
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    if let Err(e) = file.read_to_string(&mut data) {
+        error!("cannot read the state of the previous image: {:?}", e);
+        return None;
+    }
+
+    let state = State::parse(&data);
+    for fd in state.fds() {
+        let _ = set_cloexec(fd, true);
+    }
+
+    Some(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proctable::{test_process, ProcessState};
+
+    #[test]
+    fn round_trips_the_state() {
+        let state = State {
+            master: Some(5),
+            master_pid: Some(Pid::from_raw(42)),
+            processes: vec![
+                test_process(100, &["sh", "-c", "echo 'a b' \"c\"\n"],
+                             ProcessState::Running, Some("web server")),
+                test_process(101, &["sleep", "1"], ProcessState::Stopped,
+                             None),
+                test_process(102, &["true"], ProcessState::Exited(3),
+                             Some("x")),
+                test_process(103, &[""], ProcessState::Signaled(9), None),
+            ],
+            streams: vec![
+                (Pid::from_raw(100), Stream::Stdout, 7),
+                (Pid::from_raw(100), Stream::Stderr, 8),
+            ],
+            logs: vec![
+                (Pid::from_raw(100), Some("web server".to_string()),
+                 Stream::Stdout, "listening on \"::\"\ttab".to_string()),
+                (Pid::from_raw(100), Some("web server".to_string()),
+                 Stream::Stderr, "".to_string()),
+                (Pid::from_raw(104), None, Stream::Stdout, "-".to_string()),
+            ],
+            events: vec![
+                "EVENT EXITED 99 0\n".to_string(),
+                "EVENT SIGNALED 98 15 1\n".to_string(),
+            ],
+            dropped: 12,
+            stops: vec![(Pid::from_raw(100), Duration::from_millis(123456))],
+        };
+
+        assert_eq!(State::parse(&state.serialize()), state);
+    }
+
+    #[test]
+    fn skips_invalid_lines() {
+        let state = State::parse("MASTER 5\nBOGUS 1\nSTREAM 7 IN 3\n\
+                                  DROPPED x\nMASTERPID 42\n");
+
+        assert_eq!(state, State {
+            master: Some(5),
+            master_pid: Some(Pid::from_raw(42)),
+            ..State::default()
+        });
+    }
+}
@@ -0,0 +1,332 @@
+/* This file is part of the Aeterno init system. */
+
+/* Goal:
+ *  - Bring the machine down on SHUTDOWN, POWEROFF, REBOOT and HALT
+ *  - Stop the processes we know about, giving them a chance to exit cleanly
+ *  - Leave the file systems consistent before asking the kernel to go down
+ *
+ * The steps that affect the whole machine go through a `Backend`, so that the
+ * sequence can be run in a container, or in tests, without taking down the
+ * host.
+ */
+
+use std::fs;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::libc;
+use nix::mount::{mount, MsFlags};
+use nix::sys::reboot::{reboot, RebootMode};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+/// Time given to processes to exit after SIGKILL
+const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Kind {
+    Halt,
+    Poweroff,
+    Reboot,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Kind::Halt => "HALT",
+            Kind::Poweroff => "POWEROFF",
+            Kind::Reboot => "REBOOT",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// The steps of a shutdown that affect the whole machine
+pub trait Backend {
+    /// Flushes the file system buffers
+    fn sync(&self);
+    /// Remounts all file systems read-only
+    fn remount_read_only(&self);
+    /// Halts, powers off or reboots the machine, returning only on failure
+    fn reboot(&self, kind: Kind) -> nix::Result<()>;
+}
+
+/// The backend that really takes the machine down
+#[cfg_attr(not(feature = "native"), allow(dead_code))]
+pub struct Kernel;
+
+/// Undoes the octal escapes of whitespace in /proc/self/mounts
+#[cfg_attr(not(feature = "native"), allow(dead_code))]
+fn unescape_mount_path(path: &str) -> String {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut rest = path;
+
+    while let Some(pos) = rest.find('\\') {
+        unescaped.push_str(&rest[..pos]);
+        let code = rest.get(pos + 1..pos + 4)
+            .and_then(|code| u8::from_str_radix(code, 8).ok());
+
+        match code {
+            Some(c) => {
+                unescaped.push(c as char);
+                rest = &rest[pos + 4..];
+            },
+            None => {
+                unescaped.push('\\');
+                rest = &rest[pos + 1..];
+            },
+        }
+    }
+    unescaped.push_str(rest);
+
+    unescaped
+}
+
+impl Backend for Kernel {
+    fn sync(&self) {
+        unsafe { libc::sync() };
+    }
+
+    fn remount_read_only(&self) {
+        let mounts = match fs::read_to_string("/proc/self/mounts") {
+            Ok(mounts) => mounts,
+            Err(e) => {
+                error!("cannot list the mounted file systems: {:?}", e);
+                return;
+            },
+        };
+
+        /* Mounts on top of others come later, so remount them first */
+        for line in mounts.lines().rev() {
+            let target = match line.split(' ').nth(1) {
+                Some(target) => unescape_mount_path(target),
+                None => continue,
+            };
+
+            let res = mount(None::<&str>, target.as_str(), None::<&str>,
+                            MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+                            None::<&str>);
+            if let Err(e) = res {
+                debug!("cannot remount {} read-only: {:?}", target, e);
+            }
+        }
+    }
+
+    fn reboot(&self, kind: Kind) -> nix::Result<()> {
+        let mode = match kind {
+            Kind::Halt => RebootMode::RB_HALT_SYSTEM,
+            Kind::Poweroff => RebootMode::RB_POWER_OFF,
+            Kind::Reboot => RebootMode::RB_AUTOBOOT,
+        };
+
+        reboot(mode).map(|_| ())
+    }
+}
+
+/// A backend that only logs what it would do
+#[cfg_attr(feature = "native", allow(dead_code))]
+pub struct DryRun;
+
+impl Backend for DryRun {
+    fn sync(&self) {
+        info!("dry run: not syncing file systems");
+    }
+
+    fn remount_read_only(&self) {
+        info!("dry run: not remounting file systems read-only");
+    }
+
+    fn reboot(&self, kind: Kind) -> nix::Result<()> {
+        info!("dry run: not calling reboot(2) for {}", kind);
+        Ok(())
+    }
+}
+
+/// The backend used for the SHUTDOWN family of commands
+#[cfg(feature = "native")]
+pub fn system_backend() -> Box<dyn Backend + Send> {
+    Box::new(Kernel)
+}
+
+/// The backend used for the SHUTDOWN family of commands
+#[cfg(feature = "default")]
+pub fn system_backend() -> Box<dyn Backend + Send> {
+    Box::new(DryRun)
+}
+
+static IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Whether the machine is going down, after which no process may be started
+pub fn in_progress() -> bool {
+    IN_PROGRESS.load(Ordering::SeqCst)
+}
+
+/// Marks the shutdown as started, returns false if it already was
+pub fn begin() -> bool {
+    !IN_PROGRESS.swap(true, Ordering::SeqCst)
+}
+
+/// Sends `sig` to the tracked processes and the cgroups of their units
+fn signal_all(pids: &[Pid], units: &[String], sig: Signal) {
+    for pid in pids {
+        let _ = kill(*pid, sig);
+    }
+
+    for unit in units {
+        let _ = ::cgroup::kill_all(unit, sig);
+    }
+}
+
+/// Waits for the processes `pids` to go away, returns those that did not
+fn wait_for_exit(pids: &[Pid], timeout: Duration) -> Vec<Pid> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        /* A process is gone once it has been reaped */
+        let remaining = pids.iter()
+            .cloned()
+            .filter(|pid| {
+                ::proctable::status(*pid)
+                    .map_or_else(|| kill(*pid, None).is_ok(),
+                                 |p| p.state.is_alive())
+            })
+            .collect::<Vec<_>>();
+
+        if remaining.is_empty() || Instant::now() >= deadline {
+            return remaining;
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Takes the machine down: stops every process we know about, then has the
+/// backend sync and remount the file systems and halt, power off or reboot.
+///
+/// Processes are sent SIGTERM and given `grace` to exit before they are sent
+/// SIGKILL. Returns only if the backend did not take the machine down.
+pub fn run(kind: Kind, grace: Duration, backend: &dyn Backend) {
+    info!("Going down for {}", kind);
+    ::send_event(format!("EVENT SHUTDOWN {}\n", kind));
+
+    let mut pids = Vec::new();
+    let mut units = Vec::new();
+    for p in ::proctable::list().into_iter().filter(|p| p.state.is_alive()) {
+        pids.push(p.pid);
+        if let Some(unit) = p.unit {
+            if !units.contains(&unit) {
+                units.push(unit);
+            }
+        }
+    }
+
+    /* The master goes down along with everything else */
+    if let Some(master) = ::supervisor::stop_respawning() {
+        pids.push(master);
+    }
+
+    signal_all(&pids, &units, Signal::SIGTERM);
+    let stragglers = wait_for_exit(&pids, grace);
+
+    if !stragglers.is_empty() {
+        warn!("{} process(es) did not exit within {:?}, sending SIGKILL",
+              stragglers.len(), grace);
+        signal_all(&stragglers, &units, Signal::SIGKILL);
+
+        let survivors = wait_for_exit(&stragglers, KILL_TIMEOUT);
+        if !survivors.is_empty() {
+            error!("processes {:?} survived SIGKILL", survivors);
+        }
+    }
+
+    backend.sync();
+    backend.remount_read_only();
+    backend.sync();
+
+    if let Err(e) = backend.reboot(kind) {
+        error!("failed to {}: {:?}", kind, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::process::Command;
+
+    use nix::sys::wait::waitpid;
+
+    use proctable::ProcessState;
+
+    /// Records the calls made to it
+    struct Recorder {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl Backend for Recorder {
+        fn sync(&self) {
+            self.calls.borrow_mut().push("sync".to_string());
+        }
+
+        fn remount_read_only(&self) {
+            self.calls.borrow_mut().push("remount".to_string());
+        }
+
+        fn reboot(&self, kind: Kind) -> nix::Result<()> {
+            self.calls.borrow_mut().push(format!("reboot {}", kind));
+            Ok(())
+        }
+    }
+
+    /// Starts a tracked process, reaping it like the main loop of sys would
+    #[allow(clippy::zombie_processes)]
+    fn start(argv: &[&str]) -> Pid {
+        let mut cmd = Command::new(argv[0]);
+        cmd.args(&argv[1..]);
+
+        let argv = argv.iter().map(|a| a.to_string()).collect();
+        let child = ::proctable::spawn(&mut cmd, argv, None).unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+
+        thread::spawn(move || {
+            if let Ok(wait) = waitpid(pid, None) {
+                ::proctable::update(&wait);
+            }
+        });
+
+        pid
+    }
+
+    #[test]
+    fn unescapes_mount_paths() {
+        assert_eq!(unescape_mount_path("/mnt/a\\040b"), "/mnt/a b");
+        assert_eq!(unescape_mount_path("/plain"), "/plain");
+        assert_eq!(unescape_mount_path("/odd\\"), "/odd\\");
+    }
+
+    #[test]
+    fn stops_everything_then_reboots() {
+        let polite = start(&["sleep", "100"]);
+        let stubborn = start(&["sh", "-c",
+                               "trap '' TERM; while :; do sleep 0.1; done"]);
+        /* Give the shell time to ignore SIGTERM */
+        thread::sleep(Duration::from_millis(200));
+
+        let backend = Recorder { calls: RefCell::new(Vec::new()) };
+        let started = Instant::now();
+        run(Kind::Reboot, Duration::from_millis(500), &backend);
+
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert_eq!(::proctable::status(polite).unwrap().state,
+                   ProcessState::Signaled(Signal::SIGTERM as i32));
+        assert_eq!(::proctable::status(stubborn).unwrap().state,
+                   ProcessState::Signaled(Signal::SIGKILL as i32));
+        assert_eq!(*backend.calls.borrow(),
+                   vec!["sync", "remount", "sync", "reboot REBOOT"]);
+    }
+}
@@ -0,0 +1,184 @@
+/* This file is part of the Aeterno init system. */
+
+/* Goal:
+ *  - Receive the signals sent to sys through a signalfd, in the main loop
+ *  - Map the signals the kernel and container runtimes send to init onto
+ *    actions, such as rebooting or notifying the master
+ *
+ * As PID 1, sys handles SIGINT (Ctrl-Alt-Del), SIGPWR, SIGTERM, SIGUSR1 and
+ * SIGHUP by default. The actions can be changed through the environment, which
+ * is also the only way to have them handled when sys is not PID 1.
+ */
+
+use std::env;
+
+use nix::sys::reboot::set_cad_enabled;
+use nix::sys::signal::{Signal, SigSet};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::unistd::{getpid, Pid};
+
+/// Environment variable listing signal actions, e.g. `INT=reboot,HUP=ignore`
+const SIGNAL_ACTIONS_ENV: &str = "AETERNO_SYS_SIGNAL_ACTIONS";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Action {
+    Ignore,
+    Poweroff,
+    Reboot,
+    Halt,
+    /// Sends `EVENT SIGNAL <sig>` to the master
+    Notify,
+    /// Logs the state of sys
+    Dump,
+}
+
+/// Actions taken as PID 1, unless configured otherwise
+const DEFAULT_ACTIONS: [(Signal, Action); 5] = [
+    /* Sent by the kernel on Ctrl-Alt-Del, once it has been disabled */
+    (Signal::SIGINT, Action::Reboot),
+    (Signal::SIGPWR, Action::Poweroff),
+    /* Sent by container runtimes to stop the container */
+    (Signal::SIGTERM, Action::Poweroff),
+    (Signal::SIGUSR1, Action::Dump),
+    (Signal::SIGHUP, Action::Notify),
+];
+
+fn parse_signal_name(name: &str) -> Option<Signal> {
+    match name.trim_start_matches("SIG") {
+        "INT" => Some(Signal::SIGINT),
+        "PWR" => Some(Signal::SIGPWR),
+        "TERM" => Some(Signal::SIGTERM),
+        "USR1" => Some(Signal::SIGUSR1),
+        "USR2" => Some(Signal::SIGUSR2),
+        "HUP" => Some(Signal::SIGHUP),
+        _ => None,
+    }
+}
+
+fn parse_action(name: &str) -> Option<Action> {
+    match name {
+        "ignore" => Some(Action::Ignore),
+        "poweroff" => Some(Action::Poweroff),
+        "reboot" => Some(Action::Reboot),
+        "halt" => Some(Action::Halt),
+        "notify" => Some(Action::Notify),
+        "dump" => Some(Action::Dump),
+        _ => None,
+    }
+}
+
+/// Parses a comma separated list of `SIGNAL=action` pairs
+fn parse_actions(s: &str) -> Vec<(Signal, Action)> {
+    let mut actions = Vec::new();
+
+    for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let parsed = entry.split_once('=')
+            .and_then(|(sig, action)| {
+                Some((parse_signal_name(sig.trim())?,
+                      parse_action(action.trim())?))
+            });
+
+        match parsed {
+            Some(pair) => actions.push(pair),
+            None => warn!("ignoring invalid signal action {:?}", entry),
+        }
+    }
+
+    actions
+}
+
+/// Unblocks every signal of the calling thread.
+///
+/// Processes started by sys inherit the signals it blocks, so this must be
+/// called in each child between fork(2) and execve(2).
+pub fn unblock_all() -> nix::Result<()> {
+    SigSet::empty().thread_set_mask()
+}
+
+/// The signals of sys, as read from a signalfd
+pub struct Signals {
+    fd: SignalFd,
+    actions: Vec<(Signal, Action)>,
+}
+
+impl Signals {
+    /// Blocks SIGCHLD and the signals that have an action, and starts
+    /// receiving them through a signalfd.
+    ///
+    /// Must be called before any thread is spawned, so that every thread
+    /// inherits the signal mask and no signal is delivered elsewhere.
+    pub fn setup() -> nix::Result<Signals> {
+        let pid1 = getpid() == Pid::from_raw(1);
+        let mut actions = if pid1 {
+            DEFAULT_ACTIONS.to_vec()
+        } else {
+            Vec::new()
+        };
+
+        if let Ok(configured) = env::var(SIGNAL_ACTIONS_ENV) {
+            for (sig, action) in parse_actions(&configured) {
+                actions.retain(|&(s, _)| s != sig);
+                actions.push((sig, action));
+            }
+        }
+
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGCHLD);
+        for &(sig, _) in &actions {
+            mask.add(sig);
+        }
+        mask.thread_block()?;
+
+        let fd = SignalFd::with_flags(&mask, SfdFlags::SFD_CLOEXEC)?;
+
+        if pid1 {
+            /* Have the kernel send SIGINT instead of rebooting right away */
+            if let Err(e) = set_cad_enabled(false) {
+                warn!("cannot disable Ctrl-Alt-Del: {:?}", e);
+            }
+        }
+
+        info!("Signal actions: {:?}", actions);
+        Ok(Signals { fd, actions })
+    }
+
+    /// Blocks until a signal arrives
+    pub fn wait(&mut self) -> Option<Signal> {
+        match self.fd.read_signal() {
+            Ok(Some(info)) => Signal::from_c_int(info.ssi_signo as i32).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("failed to read from the signalfd: {:?}", e);
+                None
+            },
+        }
+    }
+
+    /// The action to take on `sig`
+    pub fn action(&self, sig: Signal) -> Action {
+        self.actions.iter()
+            .find(|&&(s, _)| s == sig)
+            .map_or(Action::Ignore, |&(_, action)| action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_actions() {
+        assert_eq!(parse_actions("INT=reboot, SIGHUP = ignore,,USR2=notify"),
+                   vec![(Signal::SIGINT, Action::Reboot),
+                        (Signal::SIGHUP, Action::Ignore),
+                        (Signal::SIGUSR2, Action::Notify)]);
+        assert_eq!(parse_actions(""), vec![]);
+    }
+
+    #[test]
+    fn skips_invalid_actions() {
+        assert_eq!(parse_actions("KILL=reboot,TERM=explode,PWR,=halt,\
+                                  PWR=halt"),
+                   vec![(Signal::SIGPWR, Action::Halt)]);
+    }
+}
@@ -0,0 +1,224 @@
+/* This file is part of the Aeterno init system. */
+
+/* Goal:
+ *  - Spawn aeterno-master once the sys socket is listening
+ *  - Respawn it (with backoff) whenever it exits or loses its connection
+ *  - Stop respawning it once the machine is going down
+ */
+
+use std::cell::RefCell;
+use std::cmp::min;
+use std::env;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+#[cfg(feature = "native")]
+const AETERNO_MASTER_PATH: &str = "/sbin/aeterno-master";
+
+#[cfg(feature = "default")]
+const AETERNO_MASTER_PATH: &str = "./target/debug/aeterno-master";
+
+/// Environment variable overriding the path of the master binary
+const AETERNO_MASTER_PATH_ENV: &str = "AETERNO_MASTER_PATH";
+
+const RESPAWN_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const RESPAWN_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// A master that survived this long resets the respawn backoff
+const RESPAWN_STABLE_AFTER: Duration = Duration::from_secs(10);
+
+/// Time given to a master that lost its connection before it is killed
+const MASTER_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+enum MasterEvent {
+    /// A child of sys has exited, it may or may not be the master
+    Exited(Pid),
+    /// The master connection was closed
+    Disconnected,
+}
+
+lazy_static! {
+    static ref supervisor: Mutex<RefCell<Option<Sender<MasterEvent>>>>
+        = Mutex::new(RefCell::new(None));
+}
+
+/// The running master, and whether a new one may still be spawned
+struct MasterState {
+    pid: Option<Pid>,
+    respawn: bool,
+}
+
+lazy_static! {
+    static ref master_state: Mutex<RefCell<MasterState>>
+        = Mutex::new(RefCell::new(MasterState {
+            pid: None,
+            respawn: true,
+        }));
+}
+
+fn notify(event: MasterEvent) {
+    let supervisor_cell = supervisor.lock().unwrap();
+    let tx = supervisor_cell.borrow();
+
+    if let Some(ref tx) = *tx {
+        let _ = tx.send(event);
+    }
+}
+
+/// Tells the supervisor that a child of sys has exited
+pub fn process_exited(pid: Pid) {
+    notify(MasterEvent::Exited(pid));
+}
+
+/// Tells the supervisor that the master connection has been closed
+pub fn master_disconnected() {
+    notify(MasterEvent::Disconnected);
+}
+
+/// Stops respawning the master, returns the pid of the running one, if any
+pub fn stop_respawning() -> Option<Pid> {
+    let state_cell = master_state.lock().unwrap();
+    let mut state = state_cell.borrow_mut();
+
+    state.respawn = false;
+    state.pid
+}
+
+/// Spawns the master unless respawning has been stopped
+fn spawn_master(path: &str) -> Option<io::Result<Pid>> {
+    /* Children are not reaped meanwhile, nor is the master handed over */
+    let _handover = ::handover_lock.lock().unwrap();
+    let state_cell = master_state.lock().unwrap();
+    let mut state = state_cell.borrow_mut();
+
+    /* Hold the lock so that stop_respawning() can not miss the new master */
+    if !state.respawn {
+        return None;
+    }
+
+    let mut cmd = Command::new(path);
+    unsafe {
+        cmd.pre_exec(|| ::signals::unblock_all().map_err(::exec::nix_to_io));
+    }
+
+    let res = cmd.spawn()
+        .map(|child| Pid::from_raw(child.id() as i32));
+    state.pid = res.as_ref().ok().cloned();
+
+    Some(res)
+}
+
+/// The pid of the running master, if any
+pub fn master_pid() -> Option<Pid> {
+    let state_cell = master_state.lock().unwrap();
+    let state = state_cell.borrow();
+
+    state.pid
+}
+
+/// Forgets the pid of the master that has exited
+fn master_exited() {
+    let state_cell = master_state.lock().unwrap();
+    state_cell.borrow_mut().pid = None;
+}
+
+fn master_path() -> String {
+    env::var(AETERNO_MASTER_PATH_ENV)
+        .unwrap_or_else(|_| AETERNO_MASTER_PATH.to_string())
+}
+
+/// Blocks until the master with the given `pid` has exited.
+///
+/// A master without a connection to sys is of no use, so if the master
+/// connection drops, the master is stopped to have it respawned.
+fn wait_for_master(rx: &Receiver<MasterEvent>, pid: Pid) {
+    for event in rx.iter() {
+        match event {
+            MasterEvent::Exited(p) if p == pid => return,
+            MasterEvent::Exited(_) => (),
+            MasterEvent::Disconnected => {
+                warn!("aeterno-master ({}) lost its connection, stopping it",
+                      pid);
+
+                ::watch_stop(pid, Instant::now() + MASTER_STOP_TIMEOUT);
+                let _ = kill(pid, Signal::SIGTERM);
+            },
+        }
+    }
+}
+
+fn supervise(rx: Receiver<MasterEvent>, mut adopted: Option<Pid>) {
+    let mut backoff = RESPAWN_BACKOFF_MIN;
+
+    loop {
+        let path = master_path();
+        let started = Instant::now();
+
+        let res = match adopted.take() {
+            Some(pid) => Some(Ok(pid)),
+            None => {
+                /* Anything that happened before this master is irrelevant */
+                while rx.try_recv().is_ok() {}
+
+                info!("Spawning aeterno-master from {}", path);
+                spawn_master(&path)
+            },
+        };
+
+        match res {
+            Some(Ok(pid)) => {
+                info!("aeterno-master running with pid {}", pid);
+
+                wait_for_master(&rx, pid);
+                warn!("aeterno-master ({}) exited", pid);
+                master_exited();
+            },
+            Some(Err(e)) => error!("failed to spawn aeterno-master from {}: \
+                                    {}", path, e),
+            None => {
+                info!("Not spawning aeterno-master, going down");
+                return;
+            },
+        }
+
+        if started.elapsed() >= RESPAWN_STABLE_AFTER {
+            backoff = RESPAWN_BACKOFF_MIN;
+        }
+
+        info!("Respawning aeterno-master in {:?}", backoff);
+        thread::sleep(backoff);
+        backoff = min(backoff * 2, RESPAWN_BACKOFF_MAX);
+    }
+}
+
+/// Starts supervising aeterno-master in a separate thread.
+///
+/// The sys socket must be listening already, so that the master can connect.
+/// `adopted` is a master that is running already, which is supervised
+/// instead of spawning a new one.
+pub fn start(adopted: Option<Pid>) {
+    let (tx, rx) = channel();
+
+    {
+        let supervisor_cell = supervisor.lock().unwrap();
+        *supervisor_cell.borrow_mut() = Some(tx);
+    }
+
+    if let Some(pid) = adopted {
+        info!("Adopting aeterno-master with pid {}", pid);
+
+        let state_cell = master_state.lock().unwrap();
+        state_cell.borrow_mut().pid = Some(pid);
+    }
+
+    thread::spawn(move || supervise(rx, adopted));
+}